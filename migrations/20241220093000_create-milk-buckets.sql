@@ -0,0 +1,7 @@
+-- Add up migration script here
+CREATE TABLE IF NOT EXISTS milk_buckets (
+    client TEXT PRIMARY KEY,
+    tokens BIGINT NOT NULL,
+    refilled_at TIMESTAMPTZ NOT NULL,
+    used_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
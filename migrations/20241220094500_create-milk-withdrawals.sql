@@ -0,0 +1,13 @@
+-- Add up migration script here
+CREATE TABLE IF NOT EXISTS milk_withdrawals (
+    id BIGSERIAL PRIMARY KEY,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    client TEXT NOT NULL,
+    tokens BIGINT NOT NULL,
+    amount DOUBLE PRECISION,
+    from_unit TEXT,
+    to_unit TEXT,
+    rate_limited BOOLEAN NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS milk_withdrawals_created_at ON milk_withdrawals (created_at);
@@ -0,0 +1,8 @@
+-- Add up migration script here
+CREATE TABLE IF NOT EXISTS milk_limits (
+    id BOOLEAN PRIMARY KEY DEFAULT TRUE CHECK (id),
+    capacity BIGINT NOT NULL,
+    refill BIGINT NOT NULL,
+    interval_seconds DOUBLE PRECISION NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
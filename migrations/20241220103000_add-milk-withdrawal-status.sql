@@ -0,0 +1,2 @@
+-- Add up migration script here
+ALTER TABLE milk_withdrawals ADD COLUMN IF NOT EXISTS status SMALLINT;
@@ -0,0 +1,37 @@
+//! `Accept` header parsing shared by the routes that answer in several
+//! formats. Each route maps the media types onto its own formats.
+
+use axum::http::header::ACCEPT;
+use axum::http::HeaderMap;
+
+/// The media types in the `Accept` header, best first: higher `q` values,
+/// then earlier entries. Types with `q=0` are left out, and an unreadable
+/// header accepts nothing. `None` without the header, for the route's
+/// default.
+pub fn ranked(headers: &HeaderMap) -> Option<Vec<&str>> {
+    let accept = headers.get(ACCEPT)?;
+    let Ok(accept) = accept.to_str() else {
+        return Some(Vec::new());
+    };
+
+    let mut ranges = accept
+        .split(',')
+        .filter_map(|range| {
+            let mut params = range.split(';').map(str::trim);
+            let media_type = params.next()?;
+            let q = match params.find_map(|param| param.strip_prefix("q=")) {
+                Some(q) => q.parse::<f32>().ok()?,
+                None => 1.0,
+            };
+            (q > 0.0).then_some((q, media_type))
+        })
+        .collect::<Vec<_>>();
+    ranges.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+
+    Some(
+        ranges
+            .into_iter()
+            .map(|(_, media_type)| media_type)
+            .collect(),
+    )
+}
@@ -1,16 +1,24 @@
 use std::{
+    collections::HashMap,
+    convert::Infallible,
     fmt::{self, Write},
     sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 
 use axum::{
-    extract::{Path, State},
-    http::StatusCode,
-    response::IntoResponse,
-    routing::{get, post},
-    Router,
+    extract::{FromRequestParts, Path, Query, State},
+    http::{header, request::Parts, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{delete, get, post},
+    Json, Router,
 };
 use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use sqlx::types::chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+use crate::accept;
 
 pub fn get_routes() -> Router {
     let state = BoardState::new();
@@ -20,106 +28,692 @@ pub fn get_routes() -> Router {
         .route("/12/reset", post(reset))
         .route("/12/place/{team}/{column}", post(place))
         .route("/12/random-board", get(random_board))
+        .route("/12/ai/{team}", post(ai))
+        .route("/12/moves", get(moves))
+        .route("/12/undo", post(undo))
+        .route("/12/replay/{n}", get(replay_to))
+        .route("/12/games", get(list_games).post(create_game))
+        .route("/12/games/{id}", delete(delete_game))
+        .route("/12/games/{id}/board", get(game_board))
+        .route("/12/games/{id}/reset", post(game_reset))
+        .route("/12/games/{id}/place/{team}/{column}", post(game_place))
+        .route("/12/games/{id}/random-board", get(game_random_board))
+        .route("/12/games/{id}/ai/{team}", post(game_ai))
+        .route("/12/games/{id}/moves", get(game_moves))
+        .route("/12/games/{id}/undo", post(game_undo))
+        .route("/12/games/{id}/replay/{n}", get(game_replay_to))
         .with_state(state)
 }
 
+/// The game behind the original `/12/board` routes. It is listed like any
+/// other, but never expires and can't be deleted.
+const DEFAULT_GAME: Uuid = Uuid::nil();
+
+/// Games untouched for this long are dropped.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+const MAX_GAMES: usize = 1000;
+
 #[derive(Clone)]
 pub struct BoardState {
-    seed: Arc<Mutex<rand::rngs::StdRng>>,
-    grid: Arc<Mutex<Grid>>,
+    games: Arc<Mutex<HashMap<Uuid, Game>>>,
 }
 
 impl BoardState {
     fn new() -> Self {
         Self {
-            seed: Arc::new(Mutex::new(rand::rngs::StdRng::seed_from_u64(2024))),
-            grid: Arc::new(Mutex::new(Grid::new())),
+            games: Arc::new(Mutex::new(HashMap::from([(
+                DEFAULT_GAME,
+                Game::new(Rules::default()),
+            )]))),
         }
     }
+
+    /// Runs `f` on the game with this ID, if it hasn't expired.
+    fn with<T>(&self, id: Uuid, f: impl FnOnce(&mut Game) -> T) -> Option<T> {
+        let mut games = self.games.lock().unwrap();
+        expire(&mut games);
+
+        let game = games.get_mut(&id)?;
+        game.used = Instant::now();
+        Some(f(game))
+    }
+
+    fn with_default<T>(&self, f: impl FnOnce(&mut Game) -> T) -> T {
+        self.with(DEFAULT_GAME, f)
+            .expect("the default game never expires")
+    }
 }
 
-async fn board(State(state): State<BoardState>) -> impl IntoResponse {
-    let grid = state.grid.lock().unwrap();
+fn expire(games: &mut HashMap<Uuid, Game>) {
+    games.retain(|&id, game| id == DEFAULT_GAME || game.used.elapsed() < IDLE_TIMEOUT);
+}
+
+struct Game {
+    seed: rand::rngs::StdRng,
+    /// The board `moves` were played on: empty, or the last random board.
+    start: Grid,
+    moves: Vec<Move>,
+    grid: Grid,
+    used: Instant,
+}
+
+#[derive(Clone, Serialize)]
+struct Move {
+    team: Item,
+    column: usize,
+    /// Counted from the bottom, like `column` from 1.
+    row: usize,
+    timestamp: DateTime<Utc>,
+}
+
+type Rejection = (StatusCode, String);
+
+impl Game {
+    fn new(rules: Rules) -> Self {
+        Self {
+            seed: rand::rngs::StdRng::seed_from_u64(2024),
+            start: Grid::new(rules),
+            moves: Vec::new(),
+            grid: Grid::new(rules),
+            used: Instant::now(),
+        }
+    }
+
+    fn view(&self, status: StatusCode, footer: Footer) -> View {
+        let over = self.grid.winner().is_some() || self.grid.full();
+        View {
+            status,
+            grid: self.grid.clone(),
+            next: self.next().filter(|_| !over),
+            footer,
+        }
+    }
+
+    fn board(&self) -> View {
+        self.view(StatusCode::OK, Footer::winner(&self.grid))
+    }
+
+    /// Whose turn it is: the other team from the last move, if any.
+    fn next(&self) -> Option<Item> {
+        self.moves.last().map(|last| last.team.opponent())
+    }
+
+    /// The board after the first `n` moves.
+    fn replay(&self, n: usize) -> Result<View, Rejection> {
+        let Some(moves) = self.moves.get(..n) else {
+            return Err((
+                StatusCode::NOT_FOUND,
+                format!("Only {} moves played\n", self.moves.len()),
+            ));
+        };
+
+        let mut grid = self.start.clone();
+        for played in moves {
+            grid.cells[grid.rules.height - played.row][played.column - 1] = played.team;
+        }
+
+        Ok(View {
+            status: StatusCode::OK,
+            footer: Footer::winner(&grid),
+            next: moves.last().map(|last| last.team.opponent()),
+            grid,
+        })
+    }
+
+    fn undo(&mut self) -> Result<View, Rejection> {
+        if self.moves.pop().is_none() {
+            return Err((StatusCode::CONFLICT, "No moves to undo\n".to_string()));
+        }
 
-    if let Some(team) = grid.winner() {
-        return (StatusCode::OK, format!("{}{} wins!\n", grid, team));
+        self.grid = self.replay(self.moves.len())?.grid;
+        Ok(self.board())
     }
 
-    (StatusCode::OK, grid.to_string())
+    fn reset(&mut self, rules: Rules) -> Result<View, Rejection> {
+        rules
+            .validate()
+            .map_err(|e| (StatusCode::BAD_REQUEST, format!("{e}\n")))?;
+
+        *self = Game::new(rules);
+        Ok(self.view(StatusCode::OK, Footer::Nothing))
+    }
+
+    fn place(&mut self, team: &str, column: usize) -> Result<View, Rejection> {
+        let Some(team) = Item::team(team) else {
+            return Err((StatusCode::BAD_REQUEST, "".to_string()));
+        };
+
+        if !(1..=self.grid.rules.width).contains(&column) {
+            return Err((StatusCode::BAD_REQUEST, "".to_string()));
+        }
+
+        if let Some(winner) = self.grid.winner() {
+            return Ok(self.view(StatusCode::SERVICE_UNAVAILABLE, Footer::Wins(winner)));
+        }
+
+        if let Some(next) = self.next().filter(|_| self.grid.rules.strict) {
+            if next != team {
+                return Ok(self.view(StatusCode::CONFLICT, Footer::ToPlay(next)));
+            }
+        }
+
+        let height = self.grid.rules.height;
+        let Some(row) = (0..height)
+            .rev()
+            .find(|&row| self.grid.cells[row][column - 1] == Item::Empty)
+        else {
+            return Ok(self.view(StatusCode::SERVICE_UNAVAILABLE, Footer::NoWinner));
+        };
+
+        self.grid.cells[row][column - 1] = team;
+        self.moves.push(Move {
+            team,
+            column,
+            row: height - row,
+            timestamp: Utc::now(),
+        });
+
+        let footer = match self.grid.winner() {
+            Some(winner) => Footer::Wins(winner),
+            None if self.grid.full() => Footer::NoWinner,
+            None => Footer::Nothing,
+        };
+        Ok(self.view(StatusCode::OK, footer))
+    }
+
+    fn random_board(&mut self) -> View {
+        self.grid = Grid::new_rand(self.grid.rules, &mut self.seed);
+        self.start = self.grid.clone();
+        self.moves.clear();
+
+        let footer = match self.grid.winner() {
+            Some(winner) => Footer::Wins(winner),
+            None => Footer::NoWinner,
+        };
+        self.view(StatusCode::OK, footer)
+    }
 }
 
-async fn reset(State(state): State<BoardState>) -> impl IntoResponse {
-    let mut seed = state.seed.lock().unwrap();
-    *seed = rand::rngs::StdRng::seed_from_u64(2024);
-    let mut grid = state.grid.lock().unwrap();
-    *grid = Grid::new();
-    (StatusCode::OK, grid.to_string())
+/// What's said under the board.
+#[derive(Clone, Copy)]
+enum Footer {
+    Nothing,
+    Wins(Item),
+    NoWinner,
+    ToPlay(Item),
 }
 
-async fn place(
-    Path((team, mut column)): Path<(String, u8)>,
-    State(state): State<BoardState>,
-) -> impl IntoResponse {
-    let team = match team.as_str() {
-        "cookie" => Item::Cookie,
-        "milk" => Item::Milk,
-        _other => return (StatusCode::BAD_REQUEST, "".to_string()),
-    };
+impl Footer {
+    fn winner(grid: &Grid) -> Self {
+        grid.winner().map_or(Footer::Nothing, Footer::Wins)
+    }
 
-    if !(1..5).contains(&column) {
-        return (StatusCode::BAD_REQUEST, "".to_string());
+    fn text(self) -> Option<String> {
+        match self {
+            Footer::Nothing => None,
+            Footer::Wins(team) => Some(format!("{} wins!", char::from(&team))),
+            Footer::NoWinner => Some("No winner.".to_string()),
+            Footer::ToPlay(team) => Some(format!("{} to play.", char::from(&team))),
+        }
     }
+}
 
-    column -= 1;
+/// A board response, before [`Format`] turns it into text, JSON or SVG.
+struct View {
+    status: StatusCode,
+    grid: Grid,
+    next: Option<Item>,
+    footer: Footer,
+}
 
-    let mut grid = state.grid.lock().unwrap();
+#[derive(Serialize)]
+struct BoardJson {
+    width: usize,
+    height: usize,
+    connect: usize,
+    /// Rows from top to bottom, `null` where empty.
+    cells: Vec<Vec<Option<Item>>>,
+    winner: Option<Item>,
+    full: bool,
+    next: Option<Item>,
+}
 
-    if let Some(team) = grid.winner() {
-        return (
-            StatusCode::SERVICE_UNAVAILABLE,
-            format!("{}{} wins!\n", grid, team),
+impl View {
+    /// The emoji board, as the endpoints have always returned it.
+    fn text(&self) -> String {
+        match self.footer.text() {
+            Some(footer) => format!("{}{footer}\n", self.grid),
+            None => self.grid.to_string(),
+        }
+    }
+
+    fn json(&self) -> BoardJson {
+        let rules = self.grid.rules;
+        BoardJson {
+            width: rules.width,
+            height: rules.height,
+            connect: rules.connect,
+            cells: self
+                .grid
+                .cells
+                .iter()
+                .map(|row| {
+                    row.iter()
+                        .map(|&item| (item != Item::Empty).then_some(item))
+                        .collect()
+                })
+                .collect(),
+            winner: self.grid.winner(),
+            full: self.grid.full(),
+            next: self.next,
+        }
+    }
+
+    /// The board laid out like the text one, with its wall on the sides and
+    /// bottom, and the footer as a caption.
+    fn svg(&self) -> String {
+        const CELL: usize = 40;
+        const CAPTION: usize = 32;
+
+        let rules = self.grid.rules;
+        let width = (rules.width + 2) * CELL;
+        let height = (rules.height + 1) * CELL + CAPTION;
+
+        let mut svg = String::new();
+        let _ = write!(
+            svg,
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="{height}" viewBox="0 0 {width} {height}">"#
         );
+        let _ = write!(
+            svg,
+            r##"<rect width="{width}" height="{}" fill="#eeeeee"/>"##,
+            (rules.height + 1) * CELL
+        );
+
+        for (row, cells) in self.grid.cells.iter().enumerate() {
+            for (column, item) in cells.iter().enumerate() {
+                let (x, y) = ((column + 1) * CELL, row * CELL);
+                let _ = write!(
+                    svg,
+                    r##"<rect x="{x}" y="{y}" width="{CELL}" height="{CELL}" fill="#111111"/>"##
+                );
+
+                let fill = match item {
+                    Item::Cookie => "#c8883a",
+                    Item::Milk => "#fafafa",
+                    Item::Empty => continue,
+                };
+                let _ = write!(
+                    svg,
+                    r##"<circle cx="{}" cy="{}" r="{}" fill="{fill}" stroke="#999999"/>"##,
+                    x + CELL / 2,
+                    y + CELL / 2,
+                    CELL * 2 / 5
+                );
+            }
+        }
+
+        if let Some(footer) = self.footer.text() {
+            let _ = write!(
+                svg,
+                r#"<text x="{}" y="{}" font-family="sans-serif" font-size="20" text-anchor="middle">{footer}</text>"#,
+                width / 2,
+                height - CAPTION / 3
+            );
+        }
+
+        svg.push_str("</svg>\n");
+        svg
+    }
+}
+
+/// The formats a board can be returned in.
+#[derive(Debug, Clone, Copy)]
+enum Format {
+    /// The emoji board.
+    Text,
+    Json,
+    Svg,
+}
+
+impl Format {
+    /// Pick the response format from the `Accept` header. Anything we don't
+    /// offer, like a browser's `text/html`, gets the text board as before.
+    fn negotiate(headers: &HeaderMap) -> Self {
+        accept::ranked(headers)
+            .unwrap_or_default()
+            .into_iter()
+            .find_map(|media_type| match media_type {
+                "text/plain" | "text/*" | "*/*" => Some(Format::Text),
+                "application/json" => Some(Format::Json),
+                "image/svg+xml" | "image/*" => Some(Format::Svg),
+                _ => None,
+            })
+            .unwrap_or(Format::Text)
     }
 
-    let mut placed = false;
+    fn render(self, view: &View) -> Response {
+        match self {
+            Format::Text => (view.status, view.text()).into_response(),
+            Format::Json => (view.status, Json(view.json())).into_response(),
+            Format::Svg => (
+                view.status,
+                [(header::CONTENT_TYPE, "image/svg+xml")],
+                view.svg(),
+            )
+                .into_response(),
+        }
+    }
 
-    for row in grid.0.iter_mut().rev() {
-        if row[column as usize] == Item::Empty {
-            row[column as usize] = team;
-            placed = true;
-            break;
+    /// Renders a board, or passes an error through as plain text.
+    fn respond(self, view: Result<View, Rejection>) -> Response {
+        match view {
+            Ok(view) => self.render(&view),
+            Err(rejection) => rejection.into_response(),
         }
     }
+}
+
+impl<S> FromRequestParts<S> for Format
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(Format::negotiate(&parts.headers))
+    }
+}
+
+async fn moves(State(state): State<BoardState>) -> Json<Vec<Move>> {
+    state.with_default(|game| Json(game.moves.clone()))
+}
+
+async fn undo(format: Format, State(state): State<BoardState>) -> Response {
+    state.with_default(|game| format.respond(game.undo()))
+}
+
+async fn replay_to(
+    format: Format,
+    Path(n): Path<usize>,
+    State(state): State<BoardState>,
+) -> Response {
+    state.with_default(|game| format.respond(game.replay(n)))
+}
+
+async fn game_moves(
+    Path(id): Path<Uuid>,
+    State(state): State<BoardState>,
+) -> Result<Json<Vec<Move>>, StatusCode> {
+    state
+        .with(id, |game| Json(game.moves.clone()))
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+async fn game_undo(
+    format: Format,
+    Path(id): Path<Uuid>,
+    State(state): State<BoardState>,
+) -> Result<Response, StatusCode> {
+    state
+        .with(id, |game| format.respond(game.undo()))
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+async fn game_replay_to(
+    format: Format,
+    Path((id, n)): Path<(Uuid, usize)>,
+    State(state): State<BoardState>,
+) -> Result<Response, StatusCode> {
+    state
+        .with(id, |game| format.respond(game.replay(n)))
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+async fn board(format: Format, State(state): State<BoardState>) -> Response {
+    state.with_default(|game| format.render(&game.board()))
+}
+
+/// Starts a new game, optionally on a different board or with strict turns,
+/// e.g. `?width=7&height=6&connect=4&strict=true`. Anything left out is the
+/// 4x4 default.
+async fn reset(
+    format: Format,
+    Query(rules): Query<Rules>,
+    State(state): State<BoardState>,
+) -> Response {
+    state.with_default(|game| format.respond(game.reset(rules)))
+}
+
+async fn place(
+    format: Format,
+    Path((team, column)): Path<(String, usize)>,
+    State(state): State<BoardState>,
+) -> Response {
+    state.with_default(|game| format.respond(game.place(&team, column)))
+}
+
+async fn random_board(format: Format, State(state): State<BoardState>) -> Response {
+    state.with_default(|game| format.render(&game.random_board()))
+}
+
+#[derive(Serialize)]
+struct GameSummary {
+    id: Uuid,
+    width: usize,
+    height: usize,
+    connect: usize,
+    winner: Option<Item>,
+    full: bool,
+    idle_seconds: u64,
+}
+
+async fn list_games(State(state): State<BoardState>) -> Json<Vec<GameSummary>> {
+    let mut games = state.games.lock().unwrap();
+    expire(&mut games);
+
+    let mut summaries: Vec<_> = games
+        .iter()
+        .map(|(&id, game)| GameSummary {
+            id,
+            width: game.grid.rules.width,
+            height: game.grid.rules.height,
+            connect: game.grid.rules.connect,
+            winner: game.grid.winner(),
+            full: game.grid.full(),
+            idle_seconds: game.used.elapsed().as_secs(),
+        })
+        .collect();
+    summaries.sort_by_key(|summary| summary.idle_seconds);
+
+    Json(summaries)
+}
+
+/// Creates a game with the same optional rules as `/12/reset`.
+async fn create_game(
+    Query(rules): Query<Rules>,
+    State(state): State<BoardState>,
+) -> impl IntoResponse {
+    if let Err(e) = rules.validate() {
+        return (StatusCode::BAD_REQUEST, format!("{e}\n")).into_response();
+    }
 
-    if !placed {
+    let mut games = state.games.lock().unwrap();
+    expire(&mut games);
+    if games.len() >= MAX_GAMES {
         return (
             StatusCode::SERVICE_UNAVAILABLE,
-            format!("{}No winner.\n", grid),
-        );
+            "Too many active games\n".to_string(),
+        )
+            .into_response();
+    }
+
+    let id = Uuid::new_v4();
+    games.insert(id, Game::new(rules));
+
+    (
+        StatusCode::CREATED,
+        [(header::LOCATION, format!("/12/games/{id}"))],
+        format!("{id}\n"),
+    )
+        .into_response()
+}
+
+async fn delete_game(Path(id): Path<Uuid>, State(state): State<BoardState>) -> StatusCode {
+    if id == DEFAULT_GAME {
+        return StatusCode::BAD_REQUEST;
     }
 
-    if let Some(team) = grid.winner() {
-        return (StatusCode::OK, format!("{}{} wins!\n", grid, team));
-    } else if grid.full() {
-        return (StatusCode::OK, format!("{}No winner.\n", grid));
+    match state.games.lock().unwrap().remove(&id) {
+        Some(_) => StatusCode::NO_CONTENT,
+        None => StatusCode::NOT_FOUND,
     }
+}
+
+async fn game_board(
+    format: Format,
+    Path(id): Path<Uuid>,
+    State(state): State<BoardState>,
+) -> Result<Response, StatusCode> {
+    state
+        .with(id, |game| format.render(&game.board()))
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+async fn game_reset(
+    format: Format,
+    Path(id): Path<Uuid>,
+    Query(rules): Query<Rules>,
+    State(state): State<BoardState>,
+) -> Result<Response, StatusCode> {
+    state
+        .with(id, |game| format.respond(game.reset(rules)))
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+async fn game_place(
+    format: Format,
+    Path((id, team, column)): Path<(Uuid, String, usize)>,
+    State(state): State<BoardState>,
+) -> Result<Response, StatusCode> {
+    state
+        .with(id, |game| format.respond(game.place(&team, column)))
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+async fn game_random_board(
+    format: Format,
+    Path(id): Path<Uuid>,
+    State(state): State<BoardState>,
+) -> Result<Response, StatusCode> {
+    state
+        .with(id, |game| format.render(&game.random_board()))
+        .ok_or(StatusCode::NOT_FOUND)
+}
 
-    (StatusCode::OK, grid.to_string())
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AiParams {
+    depth: Option<u32>,
 }
 
-async fn random_board(State(state): State<BoardState>) -> String {
-    let mut grid = state.grid.lock().unwrap();
-    let mut seed = state.seed.lock().unwrap();
+impl AiParams {
+    const DEFAULT_DEPTH: u32 = 4;
+    /// Deep enough to play well, shallow enough to answer quickly on a 7x6
+    /// board. Bigger boards are capped lower by [`ai::max_depth`].
+    const MAX_DEPTH: u32 = 8;
 
-    *grid = Grid::new_rand(&mut seed);
-    if let Some(team) = grid.winner() {
-        return format!("{}{} wins!\n", grid, team);
+    fn depth(&self) -> Result<u32, String> {
+        let depth = self.depth.unwrap_or(Self::DEFAULT_DEPTH);
+        if !(1..=Self::MAX_DEPTH).contains(&depth) {
+            return Err(format!("depth must be between 1 and {}", Self::MAX_DEPTH));
+        }
+        Ok(depth)
+    }
+}
+
+/// Lets the server place for `team` in game `id`, reporting the column it
+/// chose in the `AI-Column` header and how far it looked in `AI-Depth`. Big
+/// boards are searched less deeply than asked so that a move stays quick.
+///
+/// The search runs off the async workers and without holding the games lock,
+/// so `None` means the game went away in the meantime.
+async fn ai_place(
+    state: BoardState,
+    id: Uuid,
+    team: String,
+    params: AiParams,
+    format: Format,
+) -> Option<Response> {
+    let Some(item) = Item::team(&team) else {
+        return Some(StatusCode::BAD_REQUEST.into_response());
+    };
+    let depth = match params.depth() {
+        Ok(depth) => depth,
+        Err(e) => return Some((StatusCode::BAD_REQUEST, format!("{e}\n")).into_response()),
+    };
+
+    // A finished board has no move to make, so `place` explains why.
+    let grid = state.with(id, |game| game.grid.clone())?;
+    if grid.winner().is_some() || grid.full() {
+        return state.with(id, |game| format.respond(game.place(&team, 1)));
     }
 
-    format!("{}No winner.\n", grid)
+    let depth = depth.min(ai::max_depth(&grid.rules));
+    let (grid, column) = tokio::task::spawn_blocking(move || {
+        let column = ai::best_column(&grid, item, depth);
+        (grid, column)
+    })
+    .await
+    .expect("the search doesn't panic");
+    let column = column.expect("a board that isn't full has a free column") + 1;
+
+    state.with(id, |game| {
+        if game.grid != grid {
+            return (
+                StatusCode::CONFLICT,
+                "The board changed while thinking, try again\n",
+            )
+                .into_response();
+        }
+
+        let response = format.respond(game.place(&team, column));
+        (
+            [
+                ("ai-column", column.to_string()),
+                ("ai-depth", depth.to_string()),
+            ],
+            response,
+        )
+            .into_response()
+    })
+}
+
+async fn ai(
+    format: Format,
+    Path(team): Path<String>,
+    Query(params): Query<AiParams>,
+    State(state): State<BoardState>,
+) -> Response {
+    ai_place(state, DEFAULT_GAME, team, params, format)
+        .await
+        .expect("the default game never expires")
+}
+
+async fn game_ai(
+    format: Format,
+    Path((id, team)): Path<(Uuid, String)>,
+    Query(params): Query<AiParams>,
+    State(state): State<BoardState>,
+) -> Result<Response, StatusCode> {
+    ai_place(state, id, team, params, format)
+        .await
+        .ok_or(StatusCode::NOT_FOUND)
 }
 
-#[derive(Default, PartialEq)]
+#[derive(Clone, Copy, Default, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
 enum Item {
     Cookie,
     #[default]
@@ -127,6 +721,24 @@ enum Item {
     Milk,
 }
 
+impl Item {
+    fn team(name: &str) -> Option<Self> {
+        match name {
+            "cookie" => Some(Item::Cookie),
+            "milk" => Some(Item::Milk),
+            _other => None,
+        }
+    }
+
+    fn opponent(self) -> Self {
+        match self {
+            Item::Cookie => Item::Milk,
+            Item::Empty => Item::Empty,
+            Item::Milk => Item::Cookie,
+        }
+    }
+}
+
 impl From<&Item> for char {
     fn from(val: &Item) -> Self {
         match val {
@@ -137,24 +749,71 @@ impl From<&Item> for char {
     }
 }
 
-#[derive(Default)]
-struct Grid([[Item; 4]; 4]);
+/// The board's dimensions and how many in a row win.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+struct Rules {
+    width: usize,
+    height: usize,
+    connect: usize,
+    /// Make the teams take turns.
+    strict: bool,
+}
+
+impl Default for Rules {
+    fn default() -> Self {
+        Self {
+            width: 4,
+            height: 4,
+            connect: 4,
+            strict: false,
+        }
+    }
+}
+
+impl Rules {
+    /// Keeps the text rendering to a sensible size.
+    const MAX_SIDE: usize = 32;
+
+    fn validate(&self) -> Result<(), String> {
+        for (name, side) in [("width", self.width), ("height", self.height)] {
+            if !(1..=Self::MAX_SIDE).contains(&side) {
+                return Err(format!("{name} must be between 1 and {}", Self::MAX_SIDE));
+            }
+        }
+
+        let longest = self.width.max(self.height);
+        if !(2..=longest).contains(&self.connect) {
+            return Err(format!("connect must be between 2 and {longest}"));
+        }
+
+        Ok(())
+    }
+}
+
+/// Right, down, and the two diagonals, as `(row, column)` steps.
+const DIRECTIONS: [(isize, isize); 4] = [(0, 1), (1, 0), (1, 1), (1, -1)];
+
+#[derive(Clone, PartialEq)]
+struct Grid {
+    rules: Rules,
+    /// Rows from top to bottom.
+    cells: Vec<Vec<Item>>,
+}
 
 impl Grid {
-    fn new() -> Self {
-        Grid([
-            [Item::Empty, Item::Empty, Item::Empty, Item::Empty],
-            [Item::Empty, Item::Empty, Item::Empty, Item::Empty],
-            [Item::Empty, Item::Empty, Item::Empty, Item::Empty],
-            [Item::Empty, Item::Empty, Item::Empty, Item::Empty],
-        ])
-    }
-
-    fn new_rand(seed: &mut rand::rngs::StdRng) -> Self {
-        let mut grid = Grid::default();
-        for row in 0..4 {
-            for col in 0..4 {
-                grid.0[row][col] = if seed.random::<bool>() {
+    fn new(rules: Rules) -> Self {
+        let cells = (0..rules.height)
+            .map(|_| (0..rules.width).map(|_| Item::Empty).collect())
+            .collect();
+        Grid { rules, cells }
+    }
+
+    fn new_rand(rules: Rules, seed: &mut rand::rngs::StdRng) -> Self {
+        let mut grid = Grid::new(rules);
+        for row in grid.cells.iter_mut() {
+            for cell in row.iter_mut() {
+                *cell = if seed.random::<bool>() {
                     Item::Cookie
                 } else {
                     Item::Milk
@@ -164,47 +823,75 @@ impl Grid {
         grid
     }
 
-    fn winner(&self) -> Option<char> {
-        // Check each row
-        'row: for row in 0..4 {
-            if self.0[row][0] == Item::Empty {
-                continue;
-            }
-            for col in 0..4 {
-                if self.0[row][col] != self.0[row][0] {
-                    continue 'row;
-                }
-            }
-            return Some((&self.0[row][0]).into());
-        }
+    /// Finds `connect` in a row, checking rows from the top, then columns
+    /// from the left, then both diagonals.
+    fn winner(&self) -> Option<Item> {
+        let Rules {
+            width,
+            height,
+            connect,
+            ..
+        } = self.rules;
 
-        // Check each column
-        'col: for col in 0..4 {
-            if self.0[0][col] == Item::Empty {
-                continue;
-            }
-            for row in 0..4 {
-                if self.0[row][col] != self.0[0][col] {
-                    continue 'col;
+        for (d_row, d_col) in DIRECTIONS {
+            // Rows are scanned along each row, the rest down each column.
+            let (outer, inner) = if d_row == 0 {
+                (height, width)
+            } else {
+                (width, height)
+            };
+
+            for a in 0..outer {
+                for b in 0..inner {
+                    let (row, col) = if d_row == 0 { (a, b) } else { (b, a) };
+                    let start = self.cells[row][col];
+                    if start == Item::Empty {
+                        continue;
+                    }
+
+                    let line = (1..connect).all(|step| {
+                        let row = row as isize + d_row * step as isize;
+                        let col = col as isize + d_col * step as isize;
+                        (0..height as isize).contains(&row)
+                            && (0..width as isize).contains(&col)
+                            && self.cells[row as usize][col as usize] == start
+                    });
+                    if line {
+                        return Some(start);
+                    }
                 }
             }
-            return Some((&self.0[0][col]).into());
         }
 
-        // Check each diagnal
-        if self.0[0][0] != Item::Empty && (0..4).all(|pos| self.0[pos][pos] == self.0[0][0]) {
-            return Some((&self.0[0][0]).into());
-        }
+        None
+    }
 
-        if self.0[0][3] != Item::Empty && (0..4).all(|pos| self.0[pos][3 - pos] == self.0[0][3]) {
-            return Some((&self.0[0][3]).into());
+    /// Whether the piece at `row`, `col` is part of `connect` in a row. Much
+    /// cheaper than [`Grid::winner`] when only one cell has changed.
+    fn line_through(&self, row: usize, col: usize) -> bool {
+        let team = self.cells[row][col];
+        if team == Item::Empty {
+            return false;
         }
 
-        None
+        let run = |d_row: isize, d_col: isize| {
+            (1..)
+                .map(|step| (row as isize + d_row * step, col as isize + d_col * step))
+                .take_while(|&(row, col)| {
+                    (0..self.rules.height as isize).contains(&row)
+                        && (0..self.rules.width as isize).contains(&col)
+                        && self.cells[row as usize][col as usize] == team
+                })
+                .count()
+        };
+
+        DIRECTIONS.iter().any(|&(d_row, d_col)| {
+            1 + run(d_row, d_col) + run(-d_row, -d_col) >= self.rules.connect
+        })
     }
 
     fn full(&self) -> bool {
-        self.0
+        self.cells
             .iter()
             .all(|row| row.iter().all(|pos| pos != &Item::Empty))
     }
@@ -212,14 +899,14 @@ impl Grid {
 
 impl fmt::Display for Grid {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        for row in &self.0 {
+        for row in &self.cells {
             f.write_str("⬜")?; // Add a border at the beginning of the row
             for column in row {
                 f.write_char(column.into())?; // Add each column (converted) to the formatter
             }
             f.write_str("⬜\n")?; // Add a border at the end of the row and a newline
         }
-        for _ in 0..6 {
+        for _ in 0..self.rules.width + 2 {
             f.write_char('⬜')?; // Add the bottom border
         }
         f.write_char('\n')?;
@@ -227,3 +914,215 @@ impl fmt::Display for Grid {
         Ok(())
     }
 }
+
+/// Negamax with alpha-beta pruning over [`Grid`].
+mod ai {
+    use super::{AiParams, Grid, Item, Rules, DIRECTIONS};
+
+    /// Beats any heuristic score. Wins found sooner score higher.
+    const WIN: i32 = 1_000_000;
+
+    /// Roughly what a full-width search of a 7x6 board at depth 8 costs, in
+    /// window cells scored at the leaves. That takes tens of milliseconds.
+    const BUDGET: u64 = 7u64.pow(8) * 42 * 4;
+
+    /// The deepest search that stays within [`BUDGET`] on this board, so
+    /// wide boards get shallower searches. Between 1 and
+    /// [`AiParams::MAX_DEPTH`], which narrow boards are cheap enough for.
+    pub fn max_depth(rules: &Rules) -> u32 {
+        let (width, leaf) = (
+            rules.width as u64,
+            (rules.width * rules.height * rules.connect) as u64,
+        );
+        let mut depth = 1;
+        let mut nodes = width;
+        while depth < AiParams::MAX_DEPTH
+            && nodes.saturating_mul(width).saturating_mul(leaf) <= BUDGET
+        {
+            nodes = nodes.saturating_mul(width);
+            depth += 1;
+        }
+        depth
+    }
+
+    /// The 0-based column that's best for `team`, looking `depth` moves
+    /// ahead, or `None` if every column is full.
+    pub fn best_column(grid: &Grid, team: Item, depth: u32) -> Option<usize> {
+        let mut grid = grid.clone();
+        let mut best = None;
+        let mut alpha = -WIN * 2;
+
+        for column in columns(&grid) {
+            let Some(row) = drop(&mut grid, column, team) else {
+                continue;
+            };
+            let score = -negamax(
+                &mut grid,
+                (row, column),
+                team.opponent(),
+                depth - 1,
+                -WIN * 2,
+                -alpha,
+            );
+            grid.cells[row][column] = Item::Empty;
+
+            if best.is_none() || score > alpha {
+                alpha = score;
+                best = Some(column);
+            }
+        }
+
+        best
+    }
+
+    /// Scores the board for `team`, who is about to move. `last` is the cell
+    /// the other team just played.
+    fn negamax(
+        grid: &mut Grid,
+        last: (usize, usize),
+        team: Item,
+        depth: u32,
+        mut alpha: i32,
+        beta: i32,
+    ) -> i32 {
+        // Only the move just made can have completed a line.
+        if grid.line_through(last.0, last.1) {
+            return -(WIN + depth as i32);
+        }
+        if grid.full() {
+            return 0;
+        }
+        if depth == 0 {
+            return evaluate(grid, team);
+        }
+
+        let mut best = -WIN * 2;
+        for column in columns(grid) {
+            let Some(row) = drop(grid, column, team) else {
+                continue;
+            };
+            let score = -negamax(
+                grid,
+                (row, column),
+                team.opponent(),
+                depth - 1,
+                -beta,
+                -alpha,
+            );
+            grid.cells[row][column] = Item::Empty;
+
+            best = best.max(score);
+            alpha = alpha.max(score);
+            if alpha >= beta {
+                break;
+            }
+        }
+
+        best
+    }
+
+    /// Centre columns first: they're usually better, which makes pruning
+    /// kick in sooner.
+    fn columns(grid: &Grid) -> Vec<usize> {
+        let width = grid.rules.width;
+        let mut columns: Vec<usize> = (0..width).collect();
+        columns.sort_by_key(|&column| (2 * column).abs_diff(width - 1));
+        columns
+    }
+
+    /// Drops `team` into `column`, returning the row it landed in.
+    fn drop(grid: &mut Grid, column: usize, team: Item) -> Option<usize> {
+        let row = (0..grid.rules.height)
+            .rev()
+            .find(|&row| grid.cells[row][column] == Item::Empty)?;
+        grid.cells[row][column] = team;
+        Some(row)
+    }
+
+    /// Sums every `connect`-long window still open to one side, weighting
+    /// fuller windows more.
+    fn evaluate(grid: &Grid, team: Item) -> i32 {
+        let (width, height, connect) = (
+            grid.rules.width as isize,
+            grid.rules.height as isize,
+            grid.rules.connect as isize,
+        );
+        let mut score = 0;
+
+        for (d_row, d_col) in DIRECTIONS {
+            for row in 0..height {
+                for col in 0..width {
+                    let end = (row + d_row * (connect - 1), col + d_col * (connect - 1));
+                    if !(0..height).contains(&end.0) || !(0..width).contains(&end.1) {
+                        continue;
+                    }
+
+                    let (mut ours, mut theirs) = (0, 0);
+                    for step in 0..connect {
+                        let cell = grid.cells[(row + d_row * step) as usize]
+                            [(col + d_col * step) as usize];
+                        if cell == team {
+                            ours += 1;
+                        } else if cell != Item::Empty {
+                            theirs += 1;
+                        }
+                    }
+
+                    match (ours, theirs) {
+                        (0, 0) => {}
+                        (ours, 0) => score += ours * ours,
+                        (0, theirs) => score -= theirs * theirs,
+                        _ => {}
+                    }
+                }
+            }
+        }
+
+        score
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(width: usize, height: usize, connect: usize) -> Rules {
+        let rules = Rules {
+            width,
+            height,
+            connect,
+            strict: false,
+        };
+        assert_eq!(rules.validate(), Ok(()));
+        rules
+    }
+
+    #[test]
+    fn max_depth_keeps_the_classic_board_at_full_depth() {
+        assert_eq!(ai::max_depth(&rules(7, 6, 4)), AiParams::MAX_DEPTH);
+        assert_eq!(ai::max_depth(&rules(4, 4, 4)), AiParams::MAX_DEPTH);
+    }
+
+    #[test]
+    fn max_depth_shrinks_on_big_boards() {
+        assert_eq!(ai::max_depth(&rules(16, 16, 4)), 4);
+        assert_eq!(ai::max_depth(&rules(32, 32, 4)), 3);
+        assert_eq!(ai::max_depth(&rules(32, 32, 32)), 2);
+    }
+
+    #[test]
+    fn max_depth_handles_narrow_boards() {
+        // A single column never grows the node count.
+        assert_eq!(ai::max_depth(&rules(1, 4, 2)), AiParams::MAX_DEPTH);
+        assert_eq!(ai::max_depth(&rules(1, 32, 32)), AiParams::MAX_DEPTH);
+        assert_eq!(ai::max_depth(&rules(2, 32, 32)), AiParams::MAX_DEPTH);
+        assert_eq!(ai::max_depth(&rules(32, 1, 2)), 4);
+    }
+
+    #[test]
+    fn ai_plays_a_single_column() {
+        let grid = Grid::new(rules(1, 4, 2));
+        let depth = ai::max_depth(&grid.rules);
+        assert_eq!(ai::best_column(&grid, Item::Cookie, depth), Some(0));
+    }
+}
@@ -1,83 +1,792 @@
-use std::net::{Ipv4Addr, Ipv6Addr};
+use std::fmt;
+use std::net::{AddrParseError, IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
 
-use axum::extract::Query;
-use axum::{routing::get, Router};
-use serde::Deserialize;
+use axum::extract::FromRequestParts;
+use axum::http::header::CONTENT_TYPE;
+use axum::http::request::Parts;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::{routing::get, Json, Router};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_with::{skip_serializing_none, DeserializeFromStr, SerializeDisplay};
 
 pub fn get_routes() -> Router {
     Router::new()
-        .route("/2/dest", get(task1::dest))
-        .route("/2/key", get(task2::key))
-        .route("/2/v6/dest", get(task3::dest))
-        .route("/2/v6/key", get(task3::key))
+        .route(
+            "/2/dest",
+            get(dest).post(batch::dest).layer(batch::body_limit()),
+        )
+        .route(
+            "/2/key",
+            get(key).post(batch::key).layer(batch::body_limit()),
+        )
+        .route(
+            "/2/v6/dest",
+            get(dest).post(batch::dest).layer(batch::body_limit()),
+        )
+        .route(
+            "/2/v6/key",
+            get(key).post(batch::key).layer(batch::body_limit()),
+        )
+        .route("/2/cidr/dest", get(cidr::dest))
+        .route("/2/cidr/key", get(cidr::key))
 }
 
-mod task1 {
-    use super::*;
+/// How each octet of the key is combined with the matching octet of the
+/// source address.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Cipher {
+    /// Wrapping addition, the default for IPv4.
+    Add,
+    /// Exclusive or, the default for IPv6.
+    Xor,
+    /// Left bit rotation by the low three bits of the key.
+    Rotate,
+}
 
-    #[derive(Deserialize)]
-    pub struct Dest {
-        from: Ipv4Addr,
-        key: Ipv4Addr,
+impl Cipher {
+    fn encrypt(self, from: u8, key: u8) -> u8 {
+        match self {
+            Cipher::Add => from.wrapping_add(key),
+            Cipher::Xor => from ^ key,
+            Cipher::Rotate => from.rotate_left(key as u32 % 8),
+        }
     }
 
-    pub async fn dest(dest: Query<Dest>) -> String {
-        std::iter::zip(dest.from.octets().iter(), dest.key.octets().iter())
-            .map(|(&from, &key)| from.wrapping_add(key).to_string())
-            .collect::<Vec<String>>()
-            .join(".")
+    /// Recover the key octet, if any key maps `from` onto `to`.
+    fn decrypt(self, from: u8, to: u8) -> Option<u8> {
+        match self {
+            Cipher::Add => Some(to.wrapping_sub(from)),
+            Cipher::Xor => Some(to ^ from),
+            Cipher::Rotate => (0..8).find(|&key| from.rotate_left(key as u32) == to),
+        }
     }
 }
 
-mod task2 {
-    use super::*;
+impl fmt::Display for Cipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Cipher::Add => "add",
+            Cipher::Xor => "xor",
+            Cipher::Rotate => "rotate",
+        })
+    }
+}
 
-    #[derive(Deserialize)]
-    pub struct Key {
-        from: Ipv4Addr,
-        to: Ipv4Addr,
+/// An address family the ciphers can be applied to octet by octet.
+pub trait Address: Copy + Into<IpAddr> {
+    type Octets: AsRef<[u8]> + AsMut<[u8]> + Into<Self>;
+
+    const DEFAULT_CIPHER: Cipher;
+
+    fn octets(&self) -> Self::Octets;
+
+    fn encrypt(self, key: Self, cipher: Cipher) -> Self {
+        let mut to = self.octets();
+        for (to, key) in std::iter::zip(to.as_mut(), key.octets().as_ref()) {
+            *to = cipher.encrypt(*to, *key);
+        }
+        to.into()
+    }
+
+    fn decrypt(self, to: Self, cipher: Cipher) -> Option<Self> {
+        let mut key = self.octets();
+        for (from, to) in std::iter::zip(key.as_mut(), to.octets().as_ref()) {
+            *from = cipher.decrypt(*from, *to)?;
+        }
+        Some(key.into())
+    }
+}
+
+impl Address for Ipv4Addr {
+    type Octets = [u8; 4];
+
+    const DEFAULT_CIPHER: Cipher = Cipher::Add;
+
+    fn octets(&self) -> Self::Octets {
+        Ipv4Addr::octets(self)
+    }
+}
+
+impl Address for Ipv6Addr {
+    type Octets = [u8; 16];
+
+    const DEFAULT_CIPHER: Cipher = Cipher::Xor;
+
+    fn octets(&self) -> Self::Octets {
+        Ipv6Addr::octets(self)
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    MixedFamilies,
+    NotEmbedded,
+    /// `to` doesn't embed an IPv4 address the way `from` does.
+    EmbeddedDiffers,
+    NoKey(Cipher),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::MixedFamilies => f.write_str("addresses must be of the same family"),
+            Error::NotEmbedded => f.write_str("`from` does not embed an IPv4 address"),
+            Error::EmbeddedDiffers => {
+                f.write_str("`to` does not embed an IPv4 address the way `from` does")
+            }
+            Error::NoKey(cipher) => {
+                write!(f, "no key maps `from` onto `to` with the {cipher} cipher")
+            }
+        }
+    }
+}
+
+/// An RFC 7807 problem document.
+#[skip_serializing_none]
+#[derive(Debug, Serialize)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    type_: &'static str,
+    title: &'static str,
+    status: u16,
+    detail: String,
+    /// The query parameter the problem was found in.
+    parameter: Option<String>,
+}
+
+impl Problem {
+    fn new(status: StatusCode, parameter: Option<&str>, detail: impl Into<String>) -> Self {
+        Self {
+            type_: "about:blank",
+            title: status.canonical_reason().unwrap_or_default(),
+            status: status.as_u16(),
+            detail: detail.into(),
+            parameter: parameter.map(str::to_string),
+        }
+    }
+
+    fn bad_request(parameter: Option<&str>, detail: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, parameter, detail)
+    }
+}
+
+impl From<Error> for Problem {
+    fn from(error: Error) -> Self {
+        let parameter = match error {
+            Error::MixedFamilies => None,
+            Error::NotEmbedded => Some("from"),
+            Error::EmbeddedDiffers => Some("to"),
+            Error::NoKey(_) => Some("to"),
+        };
+        Problem::bad_request(parameter, error.to_string())
+    }
+}
+
+impl IntoResponse for Problem {
+    fn into_response(self) -> Response {
+        (
+            StatusCode::from_u16(self.status).unwrap(),
+            [(CONTENT_TYPE, "application/problem+json")],
+            Json(&self),
+        )
+            .into_response()
+    }
+}
+
+/// Like [`axum::extract::Query`], but rejects with a [`Problem`] naming the
+/// parameter that failed to deserialize.
+pub struct Query<T>(pub T);
+
+impl<T, S> FromRequestParts<S> for Query<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = Problem;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let query = parts.uri.query().unwrap_or_default();
+        let deserializer =
+            serde_urlencoded::Deserializer::new(form_urlencoded::parse(query.as_bytes()));
+
+        serde_path_to_error::deserialize(deserializer)
+            .map(Query)
+            .map_err(|e| {
+                let path = e.path().to_string();
+                let detail = e.into_inner().to_string();
+                // A missing parameter fails on the whole query, so its name
+                // is only in serde's message.
+                let parameter = if path != "." {
+                    Some(path)
+                } else {
+                    detail
+                        .strip_prefix("missing field `")
+                        .and_then(|rest| rest.strip_suffix('`'))
+                        .map(str::to_string)
+                };
+                Problem::bad_request(parameter.as_deref(), detail)
+            })
+    }
+}
+
+/// An address as the caller wrote it.
+///
+/// IPv6 addresses remember whether their last 32 bits were written as a dotted
+/// quad (`::ffff:10.0.0.1`, `64:ff9b::10.0.0.1`) so results can be rendered
+/// back in the same notation.
+#[derive(Debug, Clone, Copy, DeserializeFromStr, SerializeDisplay)]
+pub struct Addr {
+    ip: IpAddr,
+    dotted: bool,
+}
+
+impl Addr {
+    fn like(&self, ip: impl Into<IpAddr>) -> Self {
+        Self {
+            ip: ip.into(),
+            dotted: self.dotted,
+        }
+    }
+}
+
+impl From<IpAddr> for Addr {
+    fn from(ip: IpAddr) -> Self {
+        Self { ip, dotted: false }
+    }
+}
+
+impl FromStr for Addr {
+    type Err = AddrParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let ip = s.parse::<IpAddr>()?;
+        Ok(Self {
+            ip,
+            dotted: ip.is_ipv6() && s.contains('.'),
+        })
+    }
+}
+
+impl fmt::Display for Addr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ip = match self.ip {
+            IpAddr::V4(ip) => return ip.fmt(f),
+            IpAddr::V6(ip) => ip,
+        };
+
+        let segments = ip.segments();
+        if !self.dotted {
+            return f.write_str(&compress(&segments));
+        }
+
+        let hex = compress(&segments[..6]);
+        let [.., a, b, c, d] = ip.octets();
+        let separator = if hex.ends_with(':') { "" } else { ":" };
+        write!(f, "{hex}{separator}{a}.{b}.{c}.{d}")
+    }
+}
+
+/// Render IPv6 segments in hex, replacing the longest run of two or more zero
+/// segments with `::` (RFC 5952).
+fn compress(segments: &[u16]) -> String {
+    let mut longest = 0..0;
+    let mut start = 0;
+    for (i, segment) in segments.iter().enumerate() {
+        if *segment != 0 {
+            start = i + 1;
+        } else if i + 1 - start > longest.len() {
+            longest = start..i + 1;
+        }
+    }
+
+    let join = |segments: &[u16]| {
+        segments
+            .iter()
+            .map(|segment| format!("{segment:x}"))
+            .collect::<Vec<_>>()
+            .join(":")
+    };
+
+    if longest.len() < 2 {
+        return join(segments);
+    }
+    format!(
+        "{}::{}",
+        join(&segments[..longest.start]),
+        join(&segments[longest.end..])
+    )
+}
+
+/// The ways an IPv6 address can carry an IPv4 address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Embedding {
+    /// `::ffff:a.b.c.d`
+    Mapped,
+    /// `2002:aabb:ccdd::/48`
+    SixToFour,
+    /// `64:ff9b::a.b.c.d`
+    Nat64,
+}
+
+impl Embedding {
+    fn detect(ip: Ipv6Addr) -> Option<(Self, Ipv4Addr)> {
+        let embedding = match ip.segments() {
+            [0, 0, 0, 0, 0, 0xffff, _, _] => Embedding::Mapped,
+            [0x2002, ..] => Embedding::SixToFour,
+            [0x64, 0xff9b, 0, 0, 0, 0, _, _] => Embedding::Nat64,
+            _ => return None,
+        };
+        let octets = ip.octets();
+        let v4: [u8; 4] = octets[embedding.range()].try_into().unwrap();
+        Some((embedding, v4.into()))
+    }
+
+    fn range(self) -> std::ops::Range<usize> {
+        match self {
+            Embedding::Mapped | Embedding::Nat64 => 12..16,
+            Embedding::SixToFour => 2..6,
+        }
+    }
+
+    /// Replace the IPv4 address carried by `ip`, keeping every other bit.
+    fn embed(self, ip: Ipv6Addr, v4: Ipv4Addr) -> Ipv6Addr {
+        let mut octets = ip.octets();
+        octets[self.range()].copy_from_slice(&v4.octets());
+        octets.into()
     }
+}
 
-    pub async fn key(key: Query<Key>) -> String {
-        std::iter::zip(key.from.octets().iter(), key.to.octets().iter())
-            .map(|(&from, &to)| to.wrapping_sub(from).to_string())
-            .collect::<Vec<String>>()
-            .join(".")
+/// Encrypt `from` with `key`, using the family's default cipher unless one is
+/// given.
+///
+/// An IPv4 key applied to an IPv6 address encrypts the IPv4 address embedded
+/// in it.
+fn encrypt(from: Addr, key: IpAddr, cipher: Option<Cipher>) -> Result<Addr, Error> {
+    match (from.ip, key) {
+        (IpAddr::V4(ip), IpAddr::V4(key)) => {
+            Ok(from.like(ip.encrypt(key, cipher.unwrap_or(Ipv4Addr::DEFAULT_CIPHER))))
+        }
+        (IpAddr::V6(ip), IpAddr::V6(key)) => {
+            Ok(from.like(ip.encrypt(key, cipher.unwrap_or(Ipv6Addr::DEFAULT_CIPHER))))
+        }
+        (IpAddr::V6(ip), IpAddr::V4(key)) => {
+            let (embedding, v4) = Embedding::detect(ip).ok_or(Error::NotEmbedded)?;
+            let v4 = v4.encrypt(key, cipher.unwrap_or(Ipv4Addr::DEFAULT_CIPHER));
+            Ok(from.like(embedding.embed(ip, v4)))
+        }
+        _ => Err(Error::MixedFamilies),
     }
 }
 
-mod task3 {
+/// Which key `/2/key` recovers for IPv6 addresses.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Family {
+    /// The key for the whole address.
+    #[default]
+    V6,
+    /// The IPv4 key for the IPv4 addresses embedded in them.
+    V4,
+}
+
+/// Recover the key that encrypts `from` into `to`, using the family's default
+/// cipher unless one is given.
+///
+/// With [`Family::V4`], both IPv6 addresses must embed an IPv4 address the
+/// same way and differ only in it, and the key is the IPv4 key for the
+/// embedded addresses.
+fn decrypt(from: Addr, to: Addr, cipher: Option<Cipher>, family: Family) -> Result<Addr, Error> {
+    match (from.ip, to.ip, family) {
+        (IpAddr::V4(from), IpAddr::V4(to), _) => {
+            let cipher = cipher.unwrap_or(Ipv4Addr::DEFAULT_CIPHER);
+            from.decrypt(to, cipher)
+                .map(|key| IpAddr::from(key).into())
+                .ok_or(Error::NoKey(cipher))
+        }
+        (IpAddr::V6(ip), IpAddr::V6(to_ip), Family::V6) => {
+            let cipher = cipher.unwrap_or(Ipv6Addr::DEFAULT_CIPHER);
+            ip.decrypt(to_ip, cipher)
+                .map(|key| from.like(key))
+                .ok_or(Error::NoKey(cipher))
+        }
+        (IpAddr::V6(ip), IpAddr::V6(to_ip), Family::V4) => {
+            let (embedding, from_v4) = Embedding::detect(ip).ok_or(Error::NotEmbedded)?;
+            let to_v4 = match Embedding::detect(to_ip) {
+                Some((to_embedding, to_v4))
+                    if to_embedding == embedding
+                        && embedding.embed(ip, Ipv4Addr::UNSPECIFIED)
+                            == embedding.embed(to_ip, Ipv4Addr::UNSPECIFIED) =>
+                {
+                    to_v4
+                }
+                _ => return Err(Error::EmbeddedDiffers),
+            };
+
+            let cipher = cipher.unwrap_or(Ipv4Addr::DEFAULT_CIPHER);
+            from_v4
+                .decrypt(to_v4, cipher)
+                .map(|key| IpAddr::from(key).into())
+                .ok_or(Error::NoKey(cipher))
+        }
+        _ => Err(Error::MixedFamilies),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct Dest {
+    from: Addr,
+    key: IpAddr,
+    cipher: Option<Cipher>,
+}
+
+pub async fn dest(Query(dest): Query<Dest>) -> Result<String, Problem> {
+    Ok(encrypt(dest.from, dest.key, dest.cipher)?.to_string())
+}
+
+#[derive(Deserialize)]
+pub struct Key {
+    from: Addr,
+    to: Addr,
+    cipher: Option<Cipher>,
+    #[serde(default)]
+    family: Family,
+}
+
+pub async fn key(Query(key): Query<Key>) -> Result<String, Problem> {
+    Ok(decrypt(key.from, key.to, key.cipher, key.family)?.to_string())
+}
+
+mod cidr {
     use super::*;
 
+    /// An IPv4 network in CIDR notation, e.g. `10.0.0.0/24`.
+    ///
+    /// The address is always normalized to the network address of the prefix.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, DeserializeFromStr, SerializeDisplay)]
+    pub struct Ipv4Net {
+        addr: Ipv4Addr,
+        prefix: u8,
+    }
+
+    impl Ipv4Net {
+        fn new(addr: Ipv4Addr, prefix: u8) -> Self {
+            Self {
+                addr: Ipv4Addr::from(addr.to_bits() & Self::mask(prefix)),
+                prefix,
+            }
+        }
+
+        fn mask(prefix: u8) -> u32 {
+            u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0)
+        }
+
+        fn network(&self) -> Ipv4Addr {
+            self.addr
+        }
+
+        fn broadcast(&self) -> Ipv4Addr {
+            Ipv4Addr::from(self.addr.to_bits() | !Self::mask(self.prefix))
+        }
+
+        /// The first and last usable host addresses.
+        ///
+        /// `/31` and `/32` networks have no network or broadcast address to
+        /// reserve (RFC 3021), so every address in them is a host.
+        fn hosts(&self) -> (Ipv4Addr, Ipv4Addr) {
+            let (network, broadcast) = (self.network(), self.broadcast());
+            if self.prefix >= 31 {
+                (network, broadcast)
+            } else {
+                (
+                    Ipv4Addr::from(network.to_bits() + 1),
+                    Ipv4Addr::from(broadcast.to_bits() - 1),
+                )
+            }
+        }
+
+        fn host_count(&self) -> u64 {
+            let size = 1u64 << (32 - self.prefix);
+            if self.prefix >= 31 {
+                size
+            } else {
+                size - 2
+            }
+        }
+    }
+
+    impl FromStr for Ipv4Net {
+        type Err = String;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            let (addr, prefix) = s.split_once('/').unwrap_or((s, "32"));
+            let addr = addr
+                .parse::<Ipv4Addr>()
+                .map_err(|e| format!("invalid address: {e}"))?;
+            let prefix = prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix| *prefix <= 32)
+                .ok_or_else(|| format!("invalid prefix length: {prefix}"))?;
+            Ok(Self::new(addr, prefix))
+        }
+    }
+
+    impl fmt::Display for Ipv4Net {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "{}/{}", self.addr, self.prefix)
+        }
+    }
+
+    #[derive(Serialize)]
+    pub struct Subnet {
+        prefix: Ipv4Net,
+        network: Ipv4Addr,
+        broadcast: Ipv4Addr,
+        first_host: Ipv4Addr,
+        last_host: Ipv4Addr,
+        hosts: u64,
+    }
+
+    impl From<Ipv4Net> for Subnet {
+        fn from(net: Ipv4Net) -> Self {
+            let (first_host, last_host) = net.hosts();
+            Self {
+                prefix: net,
+                network: net.network(),
+                broadcast: net.broadcast(),
+                first_host,
+                last_host,
+                hosts: net.host_count(),
+            }
+        }
+    }
+
     #[derive(Deserialize)]
     pub struct Dest {
-        from: Ipv6Addr,
-        key: Ipv6Addr,
+        from: Ipv4Net,
+        key: Ipv4Addr,
+        cipher: Option<Cipher>,
     }
 
-    pub async fn dest(dest: Query<Dest>) -> String {
-        let to: [u8; 16] = std::iter::zip(dest.from.octets().iter(), dest.key.octets().iter())
-            .map(|(&from, &key)| from ^ key)
-            .collect::<Vec<_>>()
-            .try_into()
-            .unwrap();
-        let to = Ipv6Addr::from(to);
-        to.to_string()
+    pub async fn dest(Query(dest): Query<Dest>) -> Json<Subnet> {
+        let cipher = dest.cipher.unwrap_or(Ipv4Addr::DEFAULT_CIPHER);
+        let to = dest.from.network().encrypt(dest.key, cipher);
+        Json(Ipv4Net::new(to, dest.from.prefix).into())
     }
 
     #[derive(Deserialize)]
     pub struct Key {
-        from: Ipv6Addr,
-        to: Ipv6Addr,
+        from: Ipv4Net,
+        to: Ipv4Net,
+        cipher: Option<Cipher>,
     }
 
-    pub async fn key(key: Query<Key>) -> String {
-        let key: [u8; 16] = std::iter::zip(key.from.octets().iter(), key.to.octets().iter())
-            .map(|(&from, &to)| to ^ from)
-            .collect::<Vec<_>>()
-            .try_into()
-            .unwrap();
-        let key = Ipv6Addr::from(key);
-        key.to_string()
+    pub async fn key(Query(key): Query<Key>) -> Result<Json<Subnet>, Problem> {
+        if key.from.prefix != key.to.prefix {
+            return Err(Problem::bad_request(
+                Some("to"),
+                format!(
+                    "prefix lengths differ: /{} and /{}",
+                    key.from.prefix, key.to.prefix
+                ),
+            ));
+        }
+
+        let cipher = key.cipher.unwrap_or(Ipv4Addr::DEFAULT_CIPHER);
+        let secret = key
+            .from
+            .network()
+            .decrypt(key.to.network(), cipher)
+            .ok_or(Error::NoKey(cipher))?;
+        Ok(Json(Ipv4Net::new(secret, key.from.prefix).into()))
+    }
+}
+
+/// Batch variants of the day 2 routes.
+///
+/// The body is either a JSON array (`application/json`) or one JSON object per
+/// line (`application/x-ndjson`). IPv4 and IPv6 pairs may be mixed freely, and
+/// each item may pick its own `cipher`. The response is streamed back as
+/// newline-delimited JSON with one result or error per item, in input order.
+///
+/// NDJSON is answered as it arrives, so it has no size limit beyond
+/// [`MAX_LINE`] per item. A JSON array has to be read whole first, up to
+/// [`MAX_BODY`].
+mod batch {
+    use std::convert::Infallible;
+
+    use axum::body::{Body, BodyDataStream, Bytes};
+    use axum::extract::{DefaultBodyLimit, FromRequest, Request};
+    use futures_util::stream::{self, BoxStream, Stream, StreamExt};
+    use serde_json::Value;
+
+    use super::*;
+
+    /// The largest JSON array batch, about a million items.
+    pub const MAX_BODY: usize = 64 * 1024 * 1024;
+
+    /// The longest NDJSON line, far more than any one item needs.
+    pub const MAX_LINE: usize = 64 * 1024;
+
+    /// Replaces axum's default 2 MB limit, which is too small for batches.
+    pub fn body_limit() -> DefaultBodyLimit {
+        DefaultBodyLimit::max(MAX_BODY)
+    }
+
+    #[skip_serializing_none]
+    #[derive(Default, Serialize)]
+    struct Outcome {
+        index: usize,
+        to: Option<Addr>,
+        key: Option<Addr>,
+        error: Option<String>,
+    }
+
+    impl Outcome {
+        fn error(index: usize, error: impl Into<String>) -> Self {
+            Self {
+                index,
+                error: Some(error.into()),
+                ..Default::default()
+            }
+        }
+    }
+
+    pub async fn dest(request: Request) -> Result<Response, Problem> {
+        let items = split(request).await?;
+
+        Ok(respond(items, |index, dest: Dest| {
+            match super::encrypt(dest.from, dest.key, dest.cipher) {
+                Ok(to) => Outcome {
+                    index,
+                    to: Some(to),
+                    ..Default::default()
+                },
+                Err(e) => Outcome::error(index, e.to_string()),
+            }
+        }))
+    }
+
+    pub async fn key(request: Request) -> Result<Response, Problem> {
+        let items = split(request).await?;
+
+        Ok(respond(items, |index, key: Key| {
+            match super::decrypt(key.from, key.to, key.cipher, key.family) {
+                Ok(key) => Outcome {
+                    index,
+                    key: Some(key),
+                    ..Default::default()
+                },
+                Err(e) => Outcome::error(index, e.to_string()),
+            }
+        }))
+    }
+
+    type Items = BoxStream<'static, Result<Value, String>>;
+
+    /// Split the body into its items without deserializing them yet, so a
+    /// malformed item only fails itself and not the whole batch.
+    async fn split(request: Request) -> Result<Items, Problem> {
+        // Only the media type matters, whatever its case or parameters.
+        let media_type = request
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|header| header.to_str().ok())
+            .and_then(|header| header.split(';').next())
+            .map(|media_type| media_type.trim().to_ascii_lowercase());
+        match media_type.as_deref() {
+            Some("application/json") => {
+                let body = Bytes::from_request(request, &())
+                    .await
+                    .map_err(|e| Problem::new(e.status(), None, e.body_text()))?;
+                let items = serde_json::from_slice::<Vec<Value>>(&body)
+                    .map_err(|e| Problem::bad_request(None, format!("invalid batch: {e}")))?;
+                Ok(stream::iter(items.into_iter().map(Ok)).boxed())
+            }
+            Some("application/x-ndjson") => {
+                Ok(lines(request.into_body().into_data_stream()).boxed())
+            }
+            _ => Err(Problem::new(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                None,
+                "expected application/json or application/x-ndjson",
+            )),
+        }
+    }
+
+    enum Phase {
+        Reading,
+        /// The body has ended; whatever is buffered is the last line.
+        Ended,
+        /// The batch can't go on, e.g. the client went away.
+        Stopped,
+    }
+
+    /// Parses each non-blank line of `body` as it arrives.
+    fn lines(body: BodyDataStream) -> impl Stream<Item = Result<Value, String>> + Send {
+        let parse = |line: &[u8]| serde_json::from_slice(line).map_err(|e| e.to_string());
+        let blank = |line: &[u8]| line.iter().all(u8::is_ascii_whitespace);
+
+        stream::unfold(
+            (body, Vec::new(), Phase::Reading),
+            move |(mut body, mut buffer, mut phase)| async move {
+                loop {
+                    if let Some(end) = buffer.iter().position(|&byte| byte == b'\n') {
+                        let line: Vec<u8> = buffer.drain(..=end).collect();
+                        if blank(&line) {
+                            continue;
+                        }
+                        return Some((parse(&line), (body, buffer, phase)));
+                    }
+
+                    match phase {
+                        Phase::Reading => {}
+                        Phase::Ended if !blank(&buffer) => {
+                            let item = parse(&buffer);
+                            return Some((item, (body, Vec::new(), Phase::Ended)));
+                        }
+                        Phase::Ended | Phase::Stopped => return None,
+                    }
+
+                    if buffer.len() > MAX_LINE {
+                        let error = format!("line longer than {MAX_LINE} bytes");
+                        return Some((Err(error), (body, Vec::new(), Phase::Stopped)));
+                    }
+
+                    match body.next().await {
+                        Some(Ok(chunk)) => buffer.extend_from_slice(&chunk),
+                        Some(Err(e)) => {
+                            let error = format!("failed to read batch: {e}");
+                            return Some((Err(error), (body, Vec::new(), Phase::Stopped)));
+                        }
+                        None => phase = Phase::Ended,
+                    }
+                }
+            },
+        )
+    }
+
+    fn respond<T, F>(items: Items, f: F) -> Response
+    where
+        T: DeserializeOwned,
+        F: Fn(usize, T) -> Outcome + Send + 'static,
+    {
+        let lines = items.enumerate().map(move |(index, item)| {
+            let outcome = item
+                .and_then(|item| serde_json::from_value::<T>(item).map_err(|e| e.to_string()))
+                .map_or_else(|error| Outcome::error(index, error), |item| f(index, item));
+            let mut line = serde_json::to_string(&outcome).unwrap();
+            line.push('\n');
+            Ok::<_, Infallible>(line)
+        });
+
+        Response::builder()
+            .header(CONTENT_TYPE, "application/x-ndjson")
+            .body(Body::from_stream(lines))
+            .unwrap()
     }
 }
@@ -1,94 +1,1148 @@
 use std::borrow::Cow;
+use std::fs;
+use std::io;
+use std::path::Path;
 use std::str::FromStr;
+use std::sync::Arc;
 
+use axum::extract::{Query, State};
 use axum::http::header::CONTENT_TYPE;
 use axum::http::HeaderMap;
 use axum::http::StatusCode;
-use axum::response::IntoResponse;
+use axum::response::{IntoResponse, Response};
 use axum::routing::post;
-use axum::Router;
+use axum::{Json, Router};
+use serde::{Deserialize, Serialize};
 
-pub fn get_routes() -> Router {
-    Router::new().route("/5/manifest", post(manifest))
+use crate::accept;
+
+pub fn get_routes(policy: Policy) -> Router {
+    Router::new()
+        .route("/5/manifest", post(manifest))
+        .route("/5/workspace", post(workspace::workspace))
+        .route("/5/convert", post(convert))
+        .with_state(Arc::new(policy))
+}
+
+type Rejection = (StatusCode, Cow<'static, str>);
+
+#[derive(Deserialize)]
+pub struct Params {
+    report: Option<ReportFormat>,
+    /// List the dependencies instead of processing the orders.
+    #[serde(default)]
+    audit: bool,
 }
 
-pub async fn manifest(headers: HeaderMap, body: String) -> Result<String, impl IntoResponse> {
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ReportFormat {
+    Json,
+}
+
+pub async fn manifest(
+    State(policy): State<Arc<Policy>>,
+    Query(params): Query<Params>,
+    headers: HeaderMap,
+    body: String,
+) -> Result<Response, Rejection> {
+    let Some(format) = Format::negotiate(&headers) else {
+        return Err((StatusCode::NOT_ACCEPTABLE, "".into()));
+    };
+
     // Convert the body to a toml string
-    let cargo_toml_content: Cow<String> =
-        match headers.get(CONTENT_TYPE).map(|header| header.as_bytes()) {
-            Some(b"application/toml") => Cow::Borrowed(&body),
-            Some(b"application/yaml") => {
-                let v = serde_yaml::from_str::<toml::Value>(&body)
-                    .map_err(|_| (StatusCode::BAD_REQUEST, "Invalid manifest"))?;
-                Cow::Owned(toml::to_string(&v).unwrap())
-            }
-            Some(b"application/json") => {
-                let v = serde_json::from_str::<toml::Value>(&body)
-                    .map_err(|_| (StatusCode::BAD_REQUEST, "Invalid manifest"))?;
-                Cow::Owned(toml::to_string(&v).unwrap())
-            }
-            _ => return Err((StatusCode::UNSUPPORTED_MEDIA_TYPE, "")),
+    let content_type = headers.get(CONTENT_TYPE).map(|header| header.as_bytes());
+    let cargo_toml_content = to_toml(content_type, &body)?;
+
+    if let Some(ReportFormat::Json) = params.report {
+        let report = report::Report::new(&cargo_toml_content, &policy);
+        let status = if report.valid {
+            StatusCode::OK
+        } else {
+            StatusCode::BAD_REQUEST
         };
+        return Ok((status, Json(report)).into_response());
+    }
 
     // Parse the manifest
     let manifest = if let Ok(manifest) = cargo_manifest::Manifest::from_str(&cargo_toml_content) {
         manifest
     } else {
-        return Err((StatusCode::BAD_REQUEST, "Invalid manifest"));
+        return Err((StatusCode::BAD_REQUEST, "Invalid manifest".into()));
     };
 
-    // Validate the keyword "Christmas 2024"
-    if let Some(true) = manifest
-        .package
-        .as_ref()
-        .and_then(|package| {
-            package
-                .keywords
-                .as_ref()
-                .map(|keywords| keywords.as_ref().as_local())
-        })
-        .flatten()
-        .map(|keywords| keywords.iter().any(|keyword| keyword == "Christmas 2024"))
-    {
-    } else {
-        return Err((StatusCode::BAD_REQUEST, "Magic keyword not provided"));
+    if params.audit {
+        let audit = audit::Audit::from_manifest(&manifest);
+        return Ok(format.render(&audit, audit::Audit::to_text));
+    }
+
+    // Validate the manifest against the policy, e.g. the keyword "Christmas 2024"
+    let violations = policy.check(&manifest);
+    if !violations.is_empty() {
+        let body = violations
+            .iter()
+            .map(|violation| violation.message.as_str())
+            .collect::<Vec<_>>()
+            .join("\n");
+        return Err((StatusCode::BAD_REQUEST, body.into()));
     }
 
     // Process the orders
-    let mut processed_orders: Vec<(String, u32)> = Vec::new();
-
-    if let Some(orders) = manifest
-        .package
-        .as_ref()
-        .and_then(|package| package.metadata.as_ref())
-        .and_then(|metadata| metadata.as_table())
-        .and_then(|metadata| metadata.get("orders"))
-        .and_then(|orders| orders.as_array())
-    {
-        orders.iter().for_each(|order| {
-            if let Some(table) = order.as_table() {
-                if let (Some(item), Some(quantity)) = (
-                    table.get("item").and_then(|item| item.as_str()),
-                    table
-                        .get("quantity")
-                        .and_then(|quantity| quantity.as_integer()),
-                ) {
-                    processed_orders.push((item.to_string(), quantity as u32));
+    let orders = orders::Orders::from_manifest(&manifest);
+
+    if orders.totals.is_empty() {
+        return Err((StatusCode::NO_CONTENT, "".into()));
+    }
+
+    // Serialize a response
+    Ok(format.render(&orders, orders::Orders::to_text))
+}
+
+/// Convert a manifest between TOML, YAML and JSON.
+///
+/// The source format is taken from `Content-Type` and the target format from
+/// `Accept`, defaulting to TOML. Key order is kept in every direction, and a
+/// TOML source is returned unchanged when TOML is requested, comments
+/// included. The manifest must parse as a Cargo manifest.
+pub async fn convert(headers: HeaderMap, body: String) -> Result<Response, Rejection> {
+    let Some(format) = Format::negotiate(&headers) else {
+        return Err((StatusCode::NOT_ACCEPTABLE, "".into()));
+    };
+
+    let content_type = headers.get(CONTENT_TYPE).map(|header| header.as_bytes());
+    let cargo_toml_content = to_toml(content_type, &body)?;
+
+    if cargo_manifest::Manifest::from_str(&cargo_toml_content).is_err() {
+        return Err((StatusCode::BAD_REQUEST, "Invalid manifest".into()));
+    }
+    let table = toml::Table::from_str(&cargo_toml_content).unwrap();
+
+    let (content_type, body) = match format {
+        Format::Text | Format::Toml => ("application/toml", cargo_toml_content.into_owned()),
+        Format::Json => ("application/json", serde_json::to_string(&table).unwrap()),
+        Format::Yaml => ("application/yaml", serde_yaml::to_string(&table).unwrap()),
+    };
+
+    Ok(([(CONTENT_TYPE, content_type)], body).into_response())
+}
+
+/// The formats the processed orders can be returned in.
+#[derive(Debug, Clone, Copy)]
+enum Format {
+    /// `item: quantity` lines.
+    Text,
+    Json,
+    Yaml,
+    Toml,
+}
+
+impl Format {
+    /// Pick the response format from the `Accept` header. Without the
+    /// header, plain text is returned.
+    fn negotiate(headers: &HeaderMap) -> Option<Self> {
+        let Some(ranges) = accept::ranked(headers) else {
+            return Some(Format::Text);
+        };
+
+        ranges.into_iter().find_map(|media_type| match media_type {
+            "text/plain" | "text/*" | "*/*" => Some(Format::Text),
+            "application/json" => Some(Format::Json),
+            "application/yaml" => Some(Format::Yaml),
+            "application/toml" => Some(Format::Toml),
+            _ => None,
+        })
+    }
+
+    /// Render `value`, using `text` for the plain text format.
+    fn render<T: Serialize>(self, value: &T, text: impl FnOnce(&T) -> String) -> Response {
+        let (content_type, body) = match self {
+            Format::Text => return text(value).into_response(),
+            Format::Json => ("application/json", serde_json::to_string(value).unwrap()),
+            Format::Yaml => ("application/yaml", serde_yaml::to_string(value).unwrap()),
+            Format::Toml => ("application/toml", toml::to_string(value).unwrap()),
+        };
+
+        ([(CONTENT_TYPE, content_type)], body).into_response()
+    }
+}
+
+/// Convert a manifest in any of the accepted formats to a toml string.
+fn to_toml<'a>(content_type: Option<&[u8]>, body: &'a str) -> Result<Cow<'a, str>, Rejection> {
+    match content_type {
+        Some(b"application/toml") => Ok(Cow::Borrowed(body)),
+        Some(b"application/yaml") => {
+            let v = serde_yaml::from_str::<toml::Value>(body)
+                .map_err(|_| (StatusCode::BAD_REQUEST, "Invalid manifest".into()))?;
+            Ok(Cow::Owned(toml::to_string(&v).unwrap()))
+        }
+        Some(b"application/json") => {
+            let v = serde_json::from_str::<toml::Value>(body)
+                .map_err(|_| (StatusCode::BAD_REQUEST, "Invalid manifest".into()))?;
+            Ok(Cow::Owned(toml::to_string(&v).unwrap()))
+        }
+        _ => Err((StatusCode::UNSUPPORTED_MEDIA_TYPE, "".into())),
+    }
+}
+
+/// Rules a manifest must satisfy before its orders are processed.
+///
+/// Loaded from `manifest_policy.toml` at startup. Without the file only the
+/// keyword "Christmas 2024" is required.
+#[derive(Debug, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Policy {
+    /// Keywords that must all be in `package.keywords`.
+    keywords: Vec<String>,
+    /// Categories that must all be in `package.categories`.
+    categories: Vec<String>,
+    /// Licenses `package.license` must be one of, unless empty.
+    licenses: Vec<String>,
+    /// Lowest accepted `package.rust-version`.
+    rust_version: Option<String>,
+}
+
+/// A policy rule a manifest failed.
+#[derive(Debug)]
+pub struct Violation {
+    /// Dotted TOML path of the key the rule checks.
+    pub path: &'static str,
+    pub message: String,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            keywords: vec!["Christmas 2024".to_string()],
+            categories: Vec::new(),
+            licenses: Vec::new(),
+            rust_version: None,
+        }
+    }
+}
+
+impl Policy {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, String> {
+        let path = path.as_ref();
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(format!("Failed to read {}: {e}", path.display())),
+        };
+
+        let policy: Self = toml::from_str(&content)
+            .map_err(|e| format!("Invalid policy {}: {e}", path.display()))?;
+
+        if let Some(rust_version) = &policy.rust_version {
+            if parse_rust_version(rust_version).is_none() {
+                return Err(format!("Invalid rust-version in policy: {rust_version}"));
+            }
+        }
+
+        Ok(policy)
+    }
+
+    /// Check the manifest against every rule, returning the ones it fails.
+    pub fn check(&self, manifest: &cargo_manifest::Manifest) -> Vec<Violation> {
+        let mut violations = Vec::new();
+        let package = manifest.package.as_ref();
+
+        let keywords = package
+            .and_then(|package| package.keywords.as_ref())
+            .and_then(|keywords| keywords.as_ref().as_local())
+            .map_or(&[][..], Vec::as_slice);
+        for keyword in &self.keywords {
+            if !keywords.contains(keyword) {
+                // A single keyword keeps the original message clients match on.
+                let message = if self.keywords.len() == 1 {
+                    "Magic keyword not provided".to_string()
+                } else {
+                    format!("Magic keyword not provided: {keyword}")
+                };
+                violations.push(Violation {
+                    path: "package.keywords",
+                    message,
+                });
+            }
+        }
+
+        let categories = package
+            .and_then(|package| package.categories.as_ref())
+            .and_then(|categories| categories.as_ref().as_local())
+            .map_or(&[][..], Vec::as_slice);
+        for category in &self.categories {
+            if !categories.contains(category) {
+                violations.push(Violation {
+                    path: "package.categories",
+                    message: format!("Category not provided: {category}"),
+                });
+            }
+        }
+
+        if !self.licenses.is_empty() {
+            let license = package
+                .and_then(|package| package.license.as_ref())
+                .and_then(|license| license.as_ref().as_local());
+            let message = match license {
+                Some(license) if self.licenses.contains(license) => None,
+                Some(license) => Some(format!(
+                    "License not allowed: {license} (allowed: {})",
+                    self.licenses.join(", ")
+                )),
+                None => Some("License not provided".to_string()),
+            };
+            if let Some(message) = message {
+                violations.push(Violation {
+                    path: "package.license",
+                    message,
+                });
+            }
+        }
+
+        if let Some(minimum) = &self.rust_version {
+            let rust_version = package
+                .and_then(|package| package.rust_version.as_ref())
+                .and_then(|rust_version| rust_version.as_ref().as_local());
+            let message = match rust_version {
+                Some(rust_version) => match parse_rust_version(rust_version) {
+                    Some(version) if Some(version) >= parse_rust_version(minimum) => None,
+                    Some(_) => Some(format!(
+                        "Rust version too old: {rust_version} (minimum: {minimum})"
+                    )),
+                    None => Some(format!("Invalid rust-version: {rust_version}")),
+                },
+                None => Some("Rust version not provided".to_string()),
+            };
+            if let Some(message) = message {
+                violations.push(Violation {
+                    path: "package.rust-version",
+                    message,
+                });
+            }
+        }
+
+        violations
+    }
+}
+
+/// Parse a `rust-version` like `1.70` or `1.70.1` into comparable parts.
+fn parse_rust_version(version: &str) -> Option<(u64, u64, u64)> {
+    let mut parts = version.split('.').map(str::parse::<u64>);
+    let major = parts.next()?.ok()?;
+    let minor = parts.next().transpose().ok()?.unwrap_or(0);
+    let patch = parts.next().transpose().ok()?.unwrap_or(0);
+    parts.next().is_none().then_some((major, minor, patch))
+}
+
+mod orders {
+    use std::collections::HashMap;
+
+    use serde::{Deserialize, Serialize};
+    use toml::Value;
+
+    /// An order from `package.metadata.orders`, e.g. `{ item = "Toy car", quantity = 2 }`.
+    #[derive(Debug, Deserialize)]
+    pub struct Order {
+        pub item: String,
+        pub quantity: u32,
+    }
+
+    /// The total quantity ordered of one item.
+    #[derive(Debug, Serialize)]
+    pub struct Total {
+        pub item: String,
+        pub quantity: u32,
+        /// Indices of the orders that make up the total.
+        pub indices: Vec<usize>,
+    }
+
+    /// An entry of `package.metadata.orders` that was left out of the totals.
+    #[derive(Debug, Serialize)]
+    pub struct Skipped {
+        pub index: usize,
+        pub reason: String,
+    }
+
+    #[derive(Debug, Default, Serialize)]
+    pub struct Orders {
+        /// One order per item, in order of first appearance, with the
+        /// quantities of duplicate orders added up.
+        pub totals: Vec<Total>,
+        pub skipped: Vec<Skipped>,
+    }
+
+    impl Orders {
+        pub fn from_manifest(manifest: &cargo_manifest::Manifest) -> Self {
+            Self::from_metadata(
+                manifest
+                    .package
+                    .as_ref()
+                    .and_then(|package| package.metadata.as_ref()),
+            )
+        }
+
+        pub fn from_workspace(workspace: &cargo_manifest::Workspace) -> Self {
+            Self::from_metadata(workspace.metadata.as_ref())
+        }
+
+        fn from_metadata(metadata: Option<&Value>) -> Self {
+            metadata
+                .and_then(|metadata| metadata.get("orders"))
+                .and_then(|orders| orders.as_array())
+                .map(|orders| Self::from_values(orders))
+                .unwrap_or_default()
+        }
+
+        pub fn to_text(&self) -> String {
+            self.totals
+                .iter()
+                .map(|order| format!("{}: {}", order.item, order.quantity))
+                .collect::<Vec<_>>()
+                .join("\n")
+        }
+
+        fn from_values(values: &[Value]) -> Self {
+            let mut orders = Orders::default();
+            let mut positions: HashMap<String, usize> = HashMap::new();
+
+            for (index, value) in values.iter().enumerate() {
+                let order = match serde_path_to_error::deserialize::<_, Order>(value.clone()) {
+                    Ok(order) => order,
+                    Err(e) => {
+                        let reason = match e.path().to_string() {
+                            path if path == "." => e.into_inner().message().to_string(),
+                            path => format!("{path}: {}", e.into_inner().message()),
+                        };
+                        orders.skipped.push(Skipped { index, reason });
+                        continue;
+                    }
+                };
+
+                match positions.get(&order.item) {
+                    Some(&position) => {
+                        let total = &mut orders.totals[position];
+                        match total.quantity.checked_add(order.quantity) {
+                            Some(quantity) => {
+                                total.quantity = quantity;
+                                total.indices.push(index);
+                            }
+                            None => orders.skipped.push(Skipped {
+                                index,
+                                reason: format!("total quantity of `{}` overflows", order.item),
+                            }),
+                        }
+                    }
+                    None => {
+                        positions.insert(order.item.clone(), orders.totals.len());
+                        orders.totals.push(Total {
+                            item: order.item,
+                            quantity: order.quantity,
+                            indices: vec![index],
+                        });
+                    }
                 }
             }
+
+            orders
+        }
+    }
+}
+
+/// Dependency listing for `?audit=true`.
+mod audit {
+    use std::fmt;
+
+    use cargo_manifest::{Dependency, DepsSet};
+    use serde::Serialize;
+    use serde_with::skip_serializing_none;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+    #[serde(rename_all = "lowercase")]
+    enum Source {
+        Registry,
+        Git,
+        Path,
+        /// `{ workspace = true }`, resolved from `workspace.dependencies`.
+        Workspace,
+    }
+
+    impl fmt::Display for Source {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str(match self {
+                Source::Registry => "registry",
+                Source::Git => "git",
+                Source::Path => "path",
+                Source::Workspace => "workspace",
+            })
+        }
+    }
+
+    #[skip_serializing_none]
+    #[derive(Debug, Serialize)]
+    struct Entry {
+        /// The table the dependency is declared in, e.g. `dev-dependencies`
+        /// or `target.'cfg(unix)'.dependencies`.
+        table: String,
+        name: String,
+        /// The crate name, if renamed with `package`.
+        package: Option<String>,
+        /// The version requirement, if any.
+        req: Option<String>,
+        source: Source,
+        registry: Option<String>,
+        git: Option<String>,
+        branch: Option<String>,
+        tag: Option<String>,
+        rev: Option<String>,
+        path: Option<String>,
+        features: Vec<String>,
+        default_features: bool,
+        optional: bool,
+        /// Things a reviewer should look at.
+        warnings: Vec<&'static str>,
+    }
+
+    impl Entry {
+        fn new(table: &str, name: &str, dependency: &Dependency) -> Self {
+            let detail = dependency.detail();
+            let source = match dependency {
+                Dependency::Inherited(_) => Source::Workspace,
+                _ if dependency.git().is_some() => Source::Git,
+                _ if detail.is_some_and(|detail| detail.path.is_some()) => Source::Path,
+                _ => Source::Registry,
+            };
+            let req = match dependency {
+                Dependency::Simple(req) => Some(req.clone()),
+                Dependency::Detailed(detail) => detail.version.clone(),
+                Dependency::Inherited(_) => None,
+            };
+
+            let mut warnings = Vec::new();
+            if source == Source::Registry && req.as_deref().is_none_or(|req| req.trim() == "*") {
+                warnings.push("wildcard version requirement");
+            }
+            if source == Source::Git && detail.is_some_and(|detail| detail.rev.is_none()) {
+                warnings.push("git dependency without a pinned rev");
+            }
+
+            Self {
+                table: table.to_string(),
+                name: name.to_string(),
+                package: dependency.package().map(str::to_string),
+                req,
+                source,
+                registry: detail.and_then(|detail| detail.registry.clone()),
+                git: dependency.git().map(str::to_string),
+                branch: detail.and_then(|detail| detail.branch.clone()),
+                tag: detail.and_then(|detail| detail.tag.clone()),
+                rev: detail.and_then(|detail| detail.rev.clone()),
+                path: detail.and_then(|detail| detail.path.clone()),
+                features: dependency.req_features().to_vec(),
+                default_features: detail
+                    .and_then(|detail| detail.default_features)
+                    .unwrap_or(true),
+                optional: dependency.optional(),
+                warnings,
+            }
+        }
+    }
+
+    #[derive(Debug, Serialize)]
+    pub struct Audit {
+        dependencies: Vec<Entry>,
+    }
+
+    impl Audit {
+        pub fn from_manifest(manifest: &cargo_manifest::Manifest) -> Self {
+            let mut audit = Audit {
+                dependencies: Vec::new(),
+            };
+
+            audit.add("dependencies", manifest.dependencies.as_ref());
+            audit.add("dev-dependencies", manifest.dev_dependencies.as_ref());
+            audit.add("build-dependencies", manifest.build_dependencies.as_ref());
+
+            for (target, deps) in manifest.target.iter().flatten() {
+                let target = format!("target.'{target}'");
+                audit.add(&format!("{target}.dependencies"), Some(&deps.dependencies));
+                audit.add(
+                    &format!("{target}.dev-dependencies"),
+                    Some(&deps.dev_dependencies),
+                );
+                audit.add(
+                    &format!("{target}.build-dependencies"),
+                    Some(&deps.build_dependencies),
+                );
+            }
+
+            if let Some(workspace) = &manifest.workspace {
+                audit.add("workspace.dependencies", workspace.dependencies.as_ref());
+            }
+
+            audit
+        }
+
+        fn add(&mut self, table: &str, deps: Option<&DepsSet>) {
+            for (name, dependency) in deps.into_iter().flatten() {
+                self.dependencies.push(Entry::new(table, name, dependency));
+            }
+        }
+
+        pub fn to_text(&self) -> String {
+            self.dependencies
+                .iter()
+                .map(|entry| {
+                    let mut line = format!("{}.{} ({})", entry.table, entry.name, entry.source);
+                    if let Some(req) = &entry.req {
+                        line.push_str(&format!(" {req}"));
+                    }
+                    if !entry.features.is_empty() {
+                        line.push_str(&format!(" features: {}", entry.features.join(", ")));
+                    }
+                    for warning in &entry.warnings {
+                        line.push_str(&format!("\n  warning: {warning}"));
+                    }
+                    line
+                })
+                .collect::<Vec<_>>()
+                .join("\n")
+        }
+    }
+}
+
+/// Diagnostics for `?report=json`.
+///
+/// `cargo_manifest` stops at the first error it meets, so the raw TOML is
+/// checked first for the common mistakes, and the parse error is only added if
+/// it isn't already explained by one of those.
+mod report {
+    use std::str::FromStr;
+
+    use serde::Serialize;
+    use toml::Value;
+
+    use super::orders::Orders;
+    use super::Policy;
+
+    const EDITIONS: [&str; 4] = ["2015", "2018", "2021", "2024"];
+
+    const DEPENDENCY_TABLES: [&str; 5] = [
+        "dependencies",
+        "dev-dependencies",
+        "dev_dependencies",
+        "build-dependencies",
+        "build_dependencies",
+    ];
+
+    /// Keys Cargo understands in a detailed dependency table. serde_ignored
+    /// can't see into these, as `cargo_manifest` reads them untagged.
+    const DEPENDENCY_KEYS: [&str; 19] = [
+        "version",
+        "registry",
+        "registry-index",
+        "path",
+        "base",
+        "git",
+        "branch",
+        "tag",
+        "rev",
+        "features",
+        "optional",
+        "default-features",
+        "default_features",
+        "package",
+        "workspace",
+        "public",
+        "artifact",
+        "lib",
+        "target",
+    ];
+
+    /// Keys that say where a dependency comes from, one of which Cargo needs.
+    const DEPENDENCY_SOURCES: [&str; 4] = ["version", "path", "git", "workspace"];
+
+    #[derive(Debug, Serialize)]
+    #[serde(rename_all = "lowercase")]
+    enum Severity {
+        Error,
+        Warning,
+    }
+
+    #[derive(Debug, Serialize)]
+    struct Diagnostic {
+        /// Dotted TOML path of the offending key, empty for the whole manifest.
+        path: String,
+        severity: Severity,
+        message: String,
+    }
+
+    #[derive(Debug, Serialize)]
+    pub struct Report {
+        pub valid: bool,
+        diagnostics: Vec<Diagnostic>,
+    }
+
+    impl Report {
+        pub fn new(content: &str, policy: &Policy) -> Self {
+            let mut report = Report {
+                valid: true,
+                diagnostics: Vec::new(),
+            };
+
+            let value = match toml::Table::from_str(content) {
+                Ok(table) => Value::Table(table),
+                Err(e) => {
+                    let message = match e.span() {
+                        Some(span) => {
+                            let line = content[..span.start].matches('\n').count() + 1;
+                            format!("line {line}: {}", e.message())
+                        }
+                        None => e.message().to_string(),
+                    };
+                    report.error("", message);
+                    return report;
+                }
+            };
+
+            report.check_package(&value);
+            report.check_dependencies(&value);
+            report.check_manifest(value, policy);
+
+            report
+        }
+
+        fn error(&mut self, path: impl Into<String>, message: impl Into<String>) {
+            self.valid = false;
+            self.push(path.into(), Severity::Error, message.into());
+        }
+
+        fn warning(&mut self, path: impl Into<String>, message: impl Into<String>) {
+            self.push(path.into(), Severity::Warning, message.into());
+        }
+
+        fn push(&mut self, path: String, severity: Severity, message: String) {
+            self.diagnostics.push(Diagnostic {
+                path,
+                severity,
+                message,
+            });
+        }
+
+        fn check_package(&mut self, value: &Value) {
+            let Some(package) = value.get("package") else {
+                if value.get("workspace").is_none() {
+                    self.error("", "neither a [package] nor a [workspace] table");
+                }
+                return;
+            };
+
+            match package.get("name") {
+                Some(Value::String(_)) => {}
+                Some(_) => self.error("package.name", "expected a string"),
+                None => self.error("package.name", "missing required field"),
+            }
+
+            if let Some(Value::String(version)) = package.get("version") {
+                if let Err(e) = semver::Version::parse(version) {
+                    self.error(
+                        "package.version",
+                        format!("invalid version `{version}`: {e}"),
+                    );
+                }
+            }
+
+            if let Some(Value::String(edition)) = package.get("edition") {
+                if !EDITIONS.contains(&edition.as_str()) {
+                    self.error(
+                        "package.edition",
+                        format!(
+                            "unknown edition `{edition}`, expected one of {}",
+                            EDITIONS.join(", ")
+                        ),
+                    );
+                }
+            }
+        }
+
+        fn check_dependencies(&mut self, value: &Value) {
+            for table in DEPENDENCY_TABLES {
+                self.check_dependency_table(table, value.get(table));
+            }
+
+            if let Some(Value::Table(targets)) = value.get("target") {
+                for (target, value) in targets {
+                    for table in DEPENDENCY_TABLES {
+                        self.check_dependency_table(
+                            &format!("target.{target}.{table}"),
+                            value.get(table),
+                        );
+                    }
+                }
+            }
+
+            if let Some(workspace) = value.get("workspace") {
+                self.check_dependency_table(
+                    "workspace.dependencies",
+                    workspace.get("dependencies"),
+                );
+            }
+        }
+
+        fn check_dependency_table(&mut self, path: &str, table: Option<&Value>) {
+            let Some(Value::Table(table)) = table else {
+                return;
+            };
+
+            for (name, dependency) in table {
+                let (path, req) = match dependency {
+                    Value::String(req) => (format!("{path}.{name}"), req),
+                    Value::Table(detail) => {
+                        for key in detail.keys() {
+                            if !DEPENDENCY_KEYS.contains(&key.as_str()) {
+                                self.warning(format!("{path}.{name}.{key}"), "unknown key");
+                            }
+                        }
+                        if !DEPENDENCY_SOURCES
+                            .iter()
+                            .any(|key| detail.contains_key(*key))
+                        {
+                            self.error(
+                                format!("{path}.{name}"),
+                                format!(
+                                    "no dependency source, expected one of {}",
+                                    DEPENDENCY_SOURCES.join(", ")
+                                ),
+                            );
+                        }
+
+                        match detail.get("version") {
+                            Some(Value::String(req)) => (format!("{path}.{name}.version"), req),
+                            Some(_) => {
+                                self.error(format!("{path}.{name}.version"), "expected a string");
+                                continue;
+                            }
+                            None => continue,
+                        }
+                    }
+                    _ => {
+                        self.error(
+                            format!("{path}.{name}"),
+                            "expected a version requirement or a table",
+                        );
+                        continue;
+                    }
+                };
+
+                if let Err(e) = semver::VersionReq::parse(req) {
+                    self.error(path, format!("invalid version requirement `{req}`: {e}"));
+                }
+            }
+        }
+
+        fn check_manifest(&mut self, value: Value, policy: &Policy) {
+            let mut unknown = Vec::new();
+            let mut callback = |path: serde_ignored::Path| unknown.push(toml_path(&path));
+            let deserializer = serde_ignored::Deserializer::new(value, &mut callback);
+            let result =
+                serde_path_to_error::deserialize::<_, cargo_manifest::Manifest>(deserializer);
+
+            for path in unknown {
+                self.warning(path, "unknown key");
+            }
+
+            match result {
+                Ok(manifest) => {
+                    if manifest.package.is_some() {
+                        for violation in policy.check(&manifest) {
+                            self.error(violation.path, violation.message);
+                        }
+                    }
+                    for skipped in Orders::from_manifest(&manifest).skipped {
+                        self.warning(
+                            format!("package.metadata.orders.{}", skipped.index),
+                            format!("order skipped: {}", skipped.reason),
+                        );
+                    }
+                }
+                Err(e) => {
+                    let path = match e.path().to_string() {
+                        path if path == "." => String::new(),
+                        path => path,
+                    };
+                    let explained = self.diagnostics.iter().any(|diagnostic| {
+                        diagnostic.path == path || diagnostic.path.starts_with(&format!("{path}."))
+                    });
+                    if !explained {
+                        self.error(path, e.into_inner().message());
+                    }
+                }
+            }
+        }
+    }
+
+    /// Render a path like `package.metadata`, leaving out the `?` segments
+    /// serde_ignored adds for options and newtypes.
+    fn toml_path(path: &serde_ignored::Path) -> String {
+        let (parent, segment) = match path {
+            serde_ignored::Path::Root => return String::new(),
+            serde_ignored::Path::Seq { parent, index } => (parent, index.to_string()),
+            serde_ignored::Path::Map { parent, key } => (parent, key.clone()),
+            serde_ignored::Path::Some { parent }
+            | serde_ignored::Path::NewtypeStruct { parent }
+            | serde_ignored::Path::NewtypeVariant { parent } => return toml_path(parent),
+        };
+
+        match toml_path(parent) {
+            parent if parent.is_empty() => segment,
+            parent => format!("{parent}.{segment}"),
+        }
+    }
+}
+
+/// Orders across a Cargo workspace.
+///
+/// The workspace root manifest is uploaded as the multipart field `workspace`,
+/// every other field is a member manifest. Each field is read in the format of
+/// its content type. Without a specific one, as `curl -F member=@Cargo.toml`
+/// sends, the file extension decides, defaulting to TOML. Members inherit
+/// `{ workspace = true }` package keys from `workspace.package` before they
+/// are parsed.
+mod workspace {
+    use axum::extract::Multipart;
+    use serde_with::skip_serializing_none;
+    use toml::{Table, Value};
+
+    use super::orders::Orders;
+    use super::*;
+
+    /// Package keys members can inherit from `workspace.package`.
+    const INHERITABLE: [&str; 16] = [
+        "authors",
+        "categories",
+        "description",
+        "documentation",
+        "edition",
+        "exclude",
+        "homepage",
+        "include",
+        "keywords",
+        "license",
+        "license-file",
+        "publish",
+        "readme",
+        "repository",
+        "rust-version",
+        "version",
+    ];
+
+    #[derive(Debug, Serialize)]
+    struct WorkspaceOrders {
+        /// Orders from `workspace.metadata.orders`.
+        workspace: Orders,
+        members: Vec<MemberOrders>,
+    }
+
+    #[skip_serializing_none]
+    #[derive(Debug, Serialize)]
+    struct MemberOrders {
+        /// The multipart field the manifest was uploaded in.
+        field: String,
+        name: Option<String>,
+        #[serde(flatten)]
+        orders: Option<Orders>,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        errors: Vec<String>,
+    }
+
+    impl WorkspaceOrders {
+        fn to_text(&self) -> String {
+            let mut sections = vec![format!("[workspace]\n{}", self.workspace.to_text())];
+            for member in &self.members {
+                let name = member.name.as_deref().unwrap_or(&member.field);
+                let body = match &member.orders {
+                    Some(orders) => orders.to_text(),
+                    None => member
+                        .errors
+                        .iter()
+                        .map(|error| format!("error: {error}"))
+                        .collect::<Vec<_>>()
+                        .join("\n"),
+                };
+                sections.push(format!("[{name}]\n{body}"));
+            }
+            sections.join("\n\n")
+        }
+    }
+
+    pub async fn workspace(
+        State(policy): State<Arc<Policy>>,
+        headers: HeaderMap,
+        mut multipart: Multipart,
+    ) -> Result<Response, Rejection> {
+        let Some(format) = Format::negotiate(&headers) else {
+            return Err((StatusCode::NOT_ACCEPTABLE, "".into()));
+        };
+
+        let mut root = None;
+        let mut members = Vec::new();
+
+        while let Some(field) = multipart
+            .next_field()
+            .await
+            .map_err(|_| (StatusCode::BAD_REQUEST, "Invalid multipart body".into()))?
+        {
+            let name = field.name().unwrap_or_default().to_string();
+            let content_type = field_type(field.content_type(), field.file_name());
+            let body = field
+                .text()
+                .await
+                .map_err(|_| (StatusCode::BAD_REQUEST, "Invalid multipart body".into()))?;
+            let content = to_toml(content_type.map(str::as_bytes), &body)?.into_owned();
+
+            if name == "workspace" {
+                root = Some(content);
+            } else {
+                members.push((name, content));
+            }
+        }
+
+        let root = root.ok_or((
+            StatusCode::BAD_REQUEST,
+            "Workspace manifest not provided".into(),
+        ))?;
+        let root = root
+            .parse::<Table>()
+            .map_err(|_| (StatusCode::BAD_REQUEST, "Invalid manifest".into()))?;
+        let manifest = <cargo_manifest::Manifest>::deserialize(Value::Table(root.clone()))
+            .map_err(|_| (StatusCode::BAD_REQUEST, "Invalid manifest".into()))?;
+        let workspace = manifest
+            .workspace
+            .as_ref()
+            .ok_or((StatusCode::BAD_REQUEST, "Not a workspace manifest".into()))?;
+
+        let inherited = root
+            .get("workspace")
+            .and_then(|workspace| workspace.get("package"))
+            .and_then(Value::as_table);
+
+        let mut response = WorkspaceOrders {
+            workspace: Orders::from_workspace(workspace),
+            members: Vec::new(),
+        };
+
+        // The root manifest can also be a package of the workspace
+        if manifest.package.is_some() {
+            response.members.push(member(
+                "workspace".to_string(),
+                Ok(root.clone()),
+                inherited,
+                &policy,
+            ));
+        }
+
+        for (field, content) in members {
+            let table = content.parse::<Table>().map_err(|_| "Invalid manifest");
+            response
+                .members
+                .push(member(field, table, inherited, &policy));
+        }
+
+        Ok(format.render(&response, WorkspaceOrders::to_text))
+    }
+
+    fn member(
+        field: String,
+        table: Result<Table, &'static str>,
+        inherited: Option<&Table>,
+        policy: &Policy,
+    ) -> MemberOrders {
+        let mut member = MemberOrders {
+            field,
+            name: None,
+            orders: None,
+            errors: Vec::new(),
+        };
+
+        let manifest = table.map_err(str::to_string).and_then(|mut table| {
+            inherit(&mut table, inherited)?;
+            <cargo_manifest::Manifest>::deserialize(Value::Table(table))
+                .map_err(|_| "Invalid manifest".to_string())
         });
+
+        match manifest {
+            Ok(manifest) => {
+                member.name = manifest
+                    .package
+                    .as_ref()
+                    .map(|package| package.name.clone());
+                let violations = policy.check(&manifest);
+                if violations.is_empty() {
+                    member.orders = Some(Orders::from_manifest(&manifest));
+                } else {
+                    member.errors = violations
+                        .into_iter()
+                        .map(|violation| violation.message)
+                        .collect();
+                }
+            }
+            Err(error) => member.errors.push(error),
+        }
+
+        member
     }
 
-    // Serialize a response
-    let response = processed_orders
-        .iter()
-        .map(|(item, quantity)| format!("{item}: {quantity}"))
-        .collect::<Vec<_>>()
-        .join("\n");
+    /// Replace `key = { workspace = true }` package keys with the value from
+    /// `workspace.package`.
+    fn inherit(table: &mut Table, inherited: Option<&Table>) -> Result<(), String> {
+        let Some(Value::Table(package)) = table.get_mut("package") else {
+            return Ok(());
+        };
 
-    if response.is_empty() {
-        return Err((StatusCode::NO_CONTENT, ""));
+        for key in INHERITABLE {
+            let inherits = package
+                .get(key)
+                .and_then(|value| value.get("workspace"))
+                .and_then(Value::as_bool)
+                .unwrap_or(false);
+            if !inherits {
+                continue;
+            }
+
+            let value = inherited
+                .and_then(|inherited| inherited.get(key))
+                .ok_or_else(|| {
+                    format!("package.{key} is inherited but workspace.package.{key} is not set")
+                })?;
+            package.insert(key.to_string(), value.clone());
+        }
+
+        Ok(())
     }
 
-    Ok(response)
+    /// The manifest format of a multipart field, `None` if unsupported.
+    fn field_type(content_type: Option<&str>, file_name: Option<&str>) -> Option<&'static str> {
+        let content_type = content_type.map(|content_type| {
+            content_type
+                .split(';')
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .to_ascii_lowercase()
+        });
+        match content_type.as_deref() {
+            Some("application/toml") => return Some("application/toml"),
+            Some("application/json") => return Some("application/json"),
+            Some("application/yaml") => return Some("application/yaml"),
+            None | Some("application/octet-stream" | "text/plain") => {}
+            Some(_) => return None,
+        }
+
+        let extension = file_name
+            .and_then(|file_name| file_name.rsplit_once('.'))
+            .map(|(_, extension)| extension.to_ascii_lowercase());
+        Some(match extension.as_deref() {
+            Some("json") => "application/json",
+            Some("yaml" | "yml") => "application/yaml",
+            _ => "application/toml",
+        })
+    }
 }
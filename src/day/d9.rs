@@ -1,152 +1,853 @@
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use axum::extract::State;
-use axum::http::{HeaderMap, StatusCode};
+use axum::extract::{Query, State};
+use axum::http::{header, HeaderMap, StatusCode};
 use axum::response::IntoResponse;
-use axum::routing::post;
-use axum::Router;
-use leaky_bucket::RateLimiter;
-use serde::{Deserialize, Serialize};
-use serde_with::skip_serializing_none;
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use serde::Deserialize;
+use sqlx::PgPool;
 
+use crate::ratelimit::{Client, Limits, IDLE_TIMEOUT};
+
+/// Startup settings for the milk bucket, read from `MILK_CAPACITY`,
+/// `MILK_REFILL`, `MILK_INTERVAL` (seconds) and `MILK_ADMIN_TOKEN`. The limits
+/// only apply until someone sets new ones through `/9/limits`.
+#[derive(Debug, Default)]
+pub struct Config {
+    pub limits: Limits,
+    /// Bearer token required by `/9/limits`. Unset, the route answers 404.
+    pub admin_token: Option<String>,
+}
+
+impl Config {
+    pub fn from_vars(var: impl Fn(&str) -> Option<String>) -> Result<Self, String> {
+        fn parse<T: std::str::FromStr>(
+            key: &str,
+            value: Option<String>,
+        ) -> Result<Option<T>, String> {
+            value
+                .map(|value| {
+                    value
+                        .trim()
+                        .parse()
+                        .map_err(|_| format!("Invalid {key}: {value}"))
+                })
+                .transpose()
+        }
+
+        let mut limits = Limits::default();
+        if let Some(capacity) = parse("MILK_CAPACITY", var("MILK_CAPACITY"))? {
+            limits.capacity = capacity;
+        }
+        if let Some(refill) = parse("MILK_REFILL", var("MILK_REFILL"))? {
+            limits.refill = refill;
+        }
+        if let Some(interval) = parse::<f64>("MILK_INTERVAL", var("MILK_INTERVAL"))? {
+            limits.interval = Duration::try_from_secs_f64(interval)
+                .map_err(|_| format!("Invalid MILK_INTERVAL: {interval}"))?;
+        }
+        limits.validate()?;
+
+        Ok(Self {
+            limits,
+            admin_token: var("MILK_ADMIN_TOKEN").filter(|token| !token.is_empty()),
+        })
+    }
+}
+
+/// The milk buckets and their limits live in Postgres, so they survive
+/// restarts and are shared between instances.
 #[derive(Debug, Clone)]
 pub struct Milk {
-    limiter: Arc<Mutex<RateLimiter>>,
+    pool: PgPool,
+    /// Used until limits are stored.
+    defaults: Limits,
+    admin_token: Option<String>,
+    swept: Arc<Mutex<Instant>>,
 }
 
 impl Milk {
-    fn new() -> Self {
-        Self {
-            limiter: Arc::new(Mutex::new(new_limiter())),
-        }
+    async fn limits(&self) -> Result<Limits, sqlx::Error> {
+        Ok(store::limits(&self.pool).await?.unwrap_or(self.defaults))
     }
-}
 
-fn new_limiter() -> RateLimiter {
-    RateLimiter::builder()
-        .initial(5)
-        .max(5)
-        .refill(1)
-        .interval(Duration::from_secs(1))
-        .build()
+    /// Drops idle, full buckets, at most once every [`IDLE_TIMEOUT`].
+    async fn sweep(&self, limits: &Limits) {
+        {
+            let mut swept = self.swept.lock().unwrap();
+            if swept.elapsed() < IDLE_TIMEOUT {
+                return;
+            }
+            *swept = Instant::now();
+        }
+
+        // Best effort: a failed sweep only leaves a few rows for the next one.
+        let _ = store::sweep(&self.pool, limits, IDLE_TIMEOUT).await;
+    }
 }
 
-pub fn get_routes() -> Router {
-    let state = Milk::new();
+pub fn get_routes(config: Config, pool: PgPool) -> Router {
+    let state = Milk {
+        pool,
+        defaults: config.limits,
+        admin_token: config.admin_token,
+        swept: Arc::new(Mutex::new(Instant::now())),
+    };
 
     Router::new()
         .route("/9/milk", post(milk))
         .route("/9/refill", post(refill))
+        .route("/9/limits", get(limits).put(set_limits))
+        .route("/9/stats", get(stats))
         .with_state(state)
 }
 
-#[skip_serializing_none]
-#[derive(Debug, Default, Deserialize, Serialize)]
-struct MilkUnits {
-    gallons: Option<f32>,
-    liters: Option<f32>,
-    litres: Option<f32>,
-    pints: Option<f32>,
-    #[serde(flatten, skip_serializing)]
-    extra: HashMap<String, serde_json::Value>,
-}
+/// Conversions marked as a withdrawal are rationed by volume: each token buys
+/// one US gallon.
+const TOKEN_MILLILITRES: f64 = 3785.411784;
 
 pub async fn milk(
     headers: HeaderMap,
+    client: Client,
     State(state): State<Milk>,
     body: String,
-) -> Result<impl IntoResponse, impl IntoResponse> {
-    if !state.limiter.lock().unwrap().try_acquire(1) {
-        return Ok((
-            StatusCode::TOO_MANY_REQUESTS,
-            "No milk available\n".to_string(),
-        ));
+) -> impl IntoResponse {
+    let Ok(limits) = state.limits().await else {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+    let conversion = convert(&headers, &body);
+    // Everything else, including bodies we can't read, costs a single token.
+    let tokens = match &conversion {
+        Ok(Some(conversion)) if conversion.withdraw => (conversion.millilitres.abs()
+            / TOKEN_MILLILITRES)
+            .ceil()
+            .max(1.0) as usize,
+        _ => 1,
+    };
+    let mut entry = ledger::Entry {
+        client: client.to_string(),
+        tokens,
+        conversion: None,
+        status: StatusCode::OK,
+    };
+
+    // No amount of waiting would make room, so this isn't a 429. It still
+    // reports the bucket, taking nothing from it, but not when to retry.
+    if tokens > limits.capacity {
+        let quota = match store::acquire(&state.pool, &limits, &client, 0).await {
+            Ok(Ok(quota) | Err(quota)) => quota,
+            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        };
+        entry.status = StatusCode::PAYLOAD_TOO_LARGE;
+        ledger::record(&state.pool, &entry).await;
+        let mut response = (
+            StatusCode::PAYLOAD_TOO_LARGE,
+            quota,
+            format!(
+                "Withdrawal of {tokens} tokens exceeds the bucket capacity of {}\n",
+                limits.capacity
+            ),
+        )
+            .into_response();
+        response.headers_mut().remove(header::RETRY_AFTER);
+        return response;
     }
 
+    let quota = match store::acquire(&state.pool, &limits, &client, tokens).await {
+        Ok(Ok(quota)) => quota,
+        Ok(Err(quota)) => {
+            entry.status = StatusCode::TOO_MANY_REQUESTS;
+            ledger::record(&state.pool, &entry).await;
+            return (
+                StatusCode::TOO_MANY_REQUESTS,
+                quota,
+                "No milk available\n".to_string(),
+            )
+                .into_response();
+        }
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    };
+    state.sweep(&limits).await;
+
+    let response = match conversion {
+        Ok(None) => (StatusCode::OK, "Milk withdrawn\n".to_string()),
+        Ok(Some(conversion)) => {
+            let converted = HashMap::from([(&conversion.key, conversion.converted)]);
+            let body = serde_json::to_string(&converted).unwrap();
+            entry.conversion = Some(conversion);
+            (StatusCode::OK, body)
+        }
+        Err(status) => (status, String::new()),
+    };
+    entry.status = response.0;
+    ledger::record(&state.pool, &entry).await;
+
+    (quota, response).into_response()
+}
+
+/// Reads a JSON body as a unit conversion. Anything else is a plain
+/// withdrawal.
+fn convert(headers: &HeaderMap, body: &str) -> Result<Option<units::Conversion>, StatusCode> {
     if let Some(b"application/json") = headers.get("Content-Type").map(|header| header.as_bytes()) {
-        let units: MilkUnits =
-            serde_json::from_str(&body).map_err(|_| (StatusCode::BAD_REQUEST, ""))?;
-        if let Some(gallons) = units.gallons {
-            if units.liters.is_some()
-                || units.litres.is_some()
-                || units.pints.is_some()
-                || !units.extra.is_empty()
-            {
-                return Err((StatusCode::BAD_REQUEST, ""));
-            }
+        let request: units::Request =
+            serde_json::from_str(body).map_err(|_| StatusCode::BAD_REQUEST)?;
+        return request.convert().map(Some).ok_or(StatusCode::BAD_REQUEST);
+    }
 
-            return Ok((
-                StatusCode::OK,
-                serde_json::to_string(&MilkUnits {
-                    liters: Some(gallons * 3.785412),
-                    ..Default::default()
-                })
-                .unwrap(),
-            ));
-        } else if let Some(liters) = units.liters {
-            if units.gallons.is_some()
-                || units.litres.is_some()
-                || units.pints.is_some()
-                || !units.extra.is_empty()
-            {
-                return Err((StatusCode::BAD_REQUEST, ""));
-            }
+    Ok(None)
+}
 
-            return Ok((
-                StatusCode::OK,
-                serde_json::to_string(&MilkUnits {
-                    gallons: Some(liters / 3.785412),
-                    ..Default::default()
-                })
-                .unwrap(),
-            ));
-        } else if let Some(litres) = units.litres {
-            if units.gallons.is_some()
-                || units.liters.is_some()
-                || units.pints.is_some()
-                || !units.extra.is_empty()
-            {
-                return Err((StatusCode::BAD_REQUEST, ""));
-            }
+#[derive(Debug, Deserialize)]
+struct StatsParams {
+    windows: Option<String>,
+}
 
-            return Ok((
-                StatusCode::OK,
-                serde_json::to_string(&MilkUnits {
-                    pints: Some(litres * 1.759754),
-                    ..Default::default()
-                })
-                .unwrap(),
-            ));
-        } else if let Some(pints) = units.pints {
-            if units.gallons.is_some()
-                || units.liters.is_some()
-                || units.litres.is_some()
-                || !units.extra.is_empty()
-            {
-                return Err((StatusCode::BAD_REQUEST, ""));
+/// Reports withdrawals over each of a comma-separated list of windows, like
+/// `?windows=5m,1h,7d`. Defaults to the last minute, hour and day.
+async fn stats(
+    Query(params): Query<StatsParams>,
+    State(state): State<Milk>,
+) -> Result<Json<Vec<ledger::Stats>>, (StatusCode, String)> {
+    let windows = params.windows.as_deref().unwrap_or("1m,1h,1d");
+    let mut stats = Vec::new();
+    for window in windows.split(',').map(str::trim) {
+        let duration = parse_window(window).ok_or_else(|| {
+            (
+                StatusCode::BAD_REQUEST,
+                format!("Invalid window: {window}\n"),
+            )
+        })?;
+        let window_stats = ledger::stats(&state.pool, window, duration)
+            .await
+            .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, String::new()))?;
+        stats.push(window_stats);
+    }
+
+    Ok(Json(stats))
+}
+
+/// Parses `90`, `90s`, `15m`, `12h` or `7d`.
+fn parse_window(window: &str) -> Option<Duration> {
+    let (count, unit) = match window.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => window.split_at(i),
+        None => (window, "s"),
+    };
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return None,
+    };
+
+    let count: u64 = count.parse().ok().filter(|&count| count > 0)?;
+    count.checked_mul(seconds).map(Duration::from_secs)
+}
+
+pub async fn refill(client: Client, State(state): State<Milk>) -> impl IntoResponse {
+    let Ok(limits) = state.limits().await else {
+        return StatusCode::INTERNAL_SERVER_ERROR;
+    };
+    match store::reset(&state.pool, &limits, &client).await {
+        Ok(()) => StatusCode::OK,
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+fn authorize(headers: &HeaderMap, state: &Milk) -> Result<(), (StatusCode, String)> {
+    // No token configured means nobody may change the limits.
+    let Some(token) = &state.admin_token else {
+        return Err((StatusCode::NOT_FOUND, String::new()));
+    };
+
+    let bearer = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    if bearer != Some(token.as_str()) {
+        return Err((
+            StatusCode::UNAUTHORIZED,
+            "Invalid admin token\n".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+async fn limits(
+    headers: HeaderMap,
+    State(state): State<Milk>,
+) -> Result<Json<Limits>, (StatusCode, String)> {
+    authorize(&headers, &state)?;
+
+    let limits = state
+        .limits()
+        .await
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, String::new()))?;
+    Ok(Json(limits))
+}
+
+async fn set_limits(
+    headers: HeaderMap,
+    State(state): State<Milk>,
+    Json(limits): Json<Limits>,
+) -> Result<Json<Limits>, (StatusCode, String)> {
+    authorize(&headers, &state)?;
+    limits
+        .validate()
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("{e}\n")))?;
+
+    store::set_limits(&state.pool, &limits)
+        .await
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, String::new()))?;
+
+    Ok(Json(limits))
+}
+
+mod units {
+    use serde::Deserialize;
+    use serde_json::{Map, Value};
+
+    pub struct Unit {
+        pub name: &'static str,
+        pub aliases: &'static [&'static str],
+        pub millilitres: f64,
+        /// What the unit converts to when the request names no target.
+        pub default_target: &'static str,
+    }
+
+    /// Every volume unit we convert between. The bare `gallons` and `pints`
+    /// keep meaning US gallons and imperial pints, as they always have.
+    pub const UNITS: &[Unit] = &[
+        Unit {
+            name: "millilitres",
+            aliases: &["milliliters", "ml"],
+            millilitres: 1.0,
+            default_target: "litres",
+        },
+        Unit {
+            name: "litres",
+            aliases: &[],
+            millilitres: 1000.0,
+            default_target: "pints",
+        },
+        Unit {
+            name: "liters",
+            aliases: &["l"],
+            millilitres: 1000.0,
+            default_target: "gallons",
+        },
+        Unit {
+            name: "us_gallons",
+            aliases: &["gallons"],
+            millilitres: 3785.411784,
+            default_target: "liters",
+        },
+        Unit {
+            name: "imperial_gallons",
+            aliases: &[],
+            millilitres: 4546.09,
+            default_target: "litres",
+        },
+        Unit {
+            name: "us_quarts",
+            aliases: &["quarts"],
+            millilitres: 946.352946,
+            default_target: "liters",
+        },
+        Unit {
+            name: "imperial_quarts",
+            aliases: &[],
+            millilitres: 1136.5225,
+            default_target: "litres",
+        },
+        Unit {
+            name: "us_pints",
+            aliases: &[],
+            millilitres: 473.176473,
+            default_target: "liters",
+        },
+        Unit {
+            name: "imperial_pints",
+            aliases: &["pints"],
+            millilitres: 568.26125,
+            default_target: "litres",
+        },
+        Unit {
+            name: "us_cups",
+            aliases: &["cups"],
+            millilitres: 236.5882365,
+            default_target: "liters",
+        },
+        Unit {
+            name: "us_fluid_ounces",
+            aliases: &["fluid_ounces", "fl_oz"],
+            millilitres: 29.5735295625,
+            default_target: "liters",
+        },
+        Unit {
+            name: "imperial_fluid_ounces",
+            aliases: &[],
+            millilitres: 28.4130625,
+            default_target: "litres",
+        },
+    ];
+
+    pub fn find(name: &str) -> Option<&'static Unit> {
+        UNITS
+            .iter()
+            .find(|unit| unit.name == name || unit.aliases.contains(&name))
+    }
+
+    /// A body like `{"gallons": 2}` or `{"cups": 3, "to": "ml"}`: exactly one
+    /// amount, keyed by its unit, and an optional target unit. With
+    /// `"withdraw": true` the amount is also taken from the bucket.
+    #[derive(Debug, Deserialize)]
+    pub struct Request {
+        to: Option<String>,
+        #[serde(default)]
+        withdraw: bool,
+        #[serde(flatten)]
+        amounts: Map<String, Value>,
+    }
+
+    /// A successful conversion: units by their registry name, plus the
+    /// target as the request spelled it, which the response is keyed by.
+    #[derive(Debug)]
+    pub struct Conversion {
+        pub from: &'static str,
+        pub to: &'static str,
+        pub key: String,
+        pub amount: f64,
+        pub millilitres: f64,
+        pub converted: f64,
+        /// Whether the amount is charged to the bucket.
+        pub withdraw: bool,
+    }
+
+    impl Request {
+        pub fn convert(&self) -> Option<Conversion> {
+            let mut amounts = self.amounts.iter();
+            let (from, amount) = amounts.next()?;
+            if amounts.next().is_some() {
+                return None;
             }
 
-            return Ok((
-                StatusCode::OK,
-                serde_json::to_string(&MilkUnits {
-                    litres: Some(pints / 1.759754),
-                    ..Default::default()
-                })
-                .unwrap(),
-            ));
-        } else {
-            return Err((StatusCode::BAD_REQUEST, ""));
+            let amount = amount.as_f64()?;
+            let from = find(from)?;
+            let key = self.to.as_deref().unwrap_or(from.default_target);
+            let to = find(key)?;
+
+            let millilitres = amount * from.millilitres;
+
+            Some(Conversion {
+                from: from.name,
+                to: to.name,
+                key: key.to_string(),
+                amount,
+                millilitres,
+                converted: millilitres / to.millilitres,
+                withdraw: self.withdraw,
+            })
         }
     }
+}
+
+mod store {
+    use std::time::Duration;
+
+    use sea_query::{Expr, Iden, LockType, OnConflict, PostgresQueryBuilder, Query, Value};
+    use sea_query_binder::SqlxBinder;
+    use sqlx::prelude::FromRow;
+    use sqlx::types::chrono::{DateTime, Utc};
+    use sqlx::PgPool;
+
+    use crate::ratelimit::{Client, Limits, Quota, Snapshot};
+
+    #[derive(Iden)]
+    enum MilkBuckets {
+        Table,
+        Client,
+        Tokens,
+        RefilledAt,
+        UsedAt,
+    }
+
+    #[derive(Iden)]
+    enum MilkLimits {
+        Table,
+        Id,
+        Capacity,
+        Refill,
+        IntervalSeconds,
+        UpdatedAt,
+    }
+
+    #[derive(FromRow)]
+    struct Row {
+        tokens: i64,
+        refilled_at: DateTime<Utc>,
+    }
+
+    #[derive(FromRow)]
+    struct LimitsRow {
+        capacity: i64,
+        refill: i64,
+        interval_seconds: f64,
+    }
+
+    /// Takes `tokens` from the client's bucket, holding its row locked so
+    /// concurrent requests from other instances queue behind us.
+    pub async fn acquire(
+        pool: &PgPool,
+        limits: &Limits,
+        client: &Client,
+        tokens: usize,
+    ) -> Result<Result<Quota, Quota>, sqlx::Error> {
+        let client = client.to_string();
+        let mut tx = pool.begin().await?;
+
+        let full = Snapshot::full(limits);
+        let (sql, values) = Query::insert()
+            .into_table(MilkBuckets::Table)
+            .columns([
+                MilkBuckets::Client,
+                MilkBuckets::Tokens,
+                MilkBuckets::RefilledAt,
+            ])
+            .values_panic([
+                client.clone().into(),
+                (full.tokens as i64).into(),
+                full.refilled.into(),
+            ])
+            .on_conflict(
+                OnConflict::column(MilkBuckets::Client)
+                    .do_nothing()
+                    .to_owned(),
+            )
+            .build_sqlx(PostgresQueryBuilder);
+        sqlx::query_with(&sql, values).execute(&mut *tx).await?;
+
+        let (sql, values) = Query::select()
+            .columns([MilkBuckets::Tokens, MilkBuckets::RefilledAt])
+            .from(MilkBuckets::Table)
+            .and_where(Expr::col(MilkBuckets::Client).eq(client.clone()))
+            .lock(LockType::Update)
+            .build_sqlx(PostgresQueryBuilder);
+        let row = sqlx::query_as_with::<_, Row, _>(&sql, values)
+            .fetch_one(&mut *tx)
+            .await?;
+
+        let stored = Snapshot {
+            tokens: row.tokens.try_into().unwrap_or_default(),
+            refilled: row.refilled_at,
+        };
+        let (outcome, snapshot) = stored.acquire(limits, tokens);
+
+        let (sql, values) = Query::update()
+            .table(MilkBuckets::Table)
+            .value(MilkBuckets::Tokens, snapshot.tokens as i64)
+            .value(MilkBuckets::RefilledAt, snapshot.refilled)
+            .value(MilkBuckets::UsedAt, Expr::current_timestamp())
+            .and_where(Expr::col(MilkBuckets::Client).eq(client))
+            .build_sqlx(PostgresQueryBuilder);
+        sqlx::query_with(&sql, values).execute(&mut *tx).await?;
+
+        tx.commit().await?;
+        Ok(outcome)
+    }
+
+    /// Fills the client's bucket back up to capacity.
+    pub async fn reset(pool: &PgPool, limits: &Limits, client: &Client) -> Result<(), sqlx::Error> {
+        let full = Snapshot::full(limits);
+        let (sql, values) = Query::insert()
+            .into_table(MilkBuckets::Table)
+            .columns([
+                MilkBuckets::Client,
+                MilkBuckets::Tokens,
+                MilkBuckets::RefilledAt,
+            ])
+            .values_panic([
+                client.to_string().into(),
+                (full.tokens as i64).into(),
+                full.refilled.into(),
+            ])
+            .on_conflict(
+                OnConflict::column(MilkBuckets::Client)
+                    .update_columns([MilkBuckets::Tokens, MilkBuckets::RefilledAt])
+                    .value(MilkBuckets::UsedAt, Expr::current_timestamp())
+                    .to_owned(),
+            )
+            .build_sqlx(PostgresQueryBuilder);
+        sqlx::query_with(&sql, values).execute(pool).await?;
+
+        Ok(())
+    }
+
+    /// The limits last set through `/9/limits`, if any.
+    pub async fn limits(pool: &PgPool) -> Result<Option<Limits>, sqlx::Error> {
+        let (sql, values) = Query::select()
+            .columns([
+                MilkLimits::Capacity,
+                MilkLimits::Refill,
+                MilkLimits::IntervalSeconds,
+            ])
+            .from(MilkLimits::Table)
+            .build_sqlx(PostgresQueryBuilder);
+        let row = sqlx::query_as_with::<_, LimitsRow, _>(&sql, values)
+            .fetch_optional(pool)
+            .await?;
+
+        // A row we can't make sense of is as good as none.
+        Ok(row.and_then(|row| {
+            let limits = Limits {
+                capacity: row.capacity.try_into().ok()?,
+                refill: row.refill.try_into().ok()?,
+                interval: Duration::try_from_secs_f64(row.interval_seconds).ok()?,
+            };
+            limits.validate().ok().map(|()| limits)
+        }))
+    }
+
+    /// Stores new limits for every instance, capping every bucket at the
+    /// new, possibly smaller, capacity.
+    pub async fn set_limits(pool: &PgPool, limits: &Limits) -> Result<(), sqlx::Error> {
+        let mut tx = pool.begin().await?;
+
+        let (sql, values) = Query::insert()
+            .into_table(MilkLimits::Table)
+            .columns([
+                MilkLimits::Id,
+                MilkLimits::Capacity,
+                MilkLimits::Refill,
+                MilkLimits::IntervalSeconds,
+            ])
+            .values_panic([
+                true.into(),
+                (limits.capacity as i64).into(),
+                (limits.refill as i64).into(),
+                limits.interval.as_secs_f64().into(),
+            ])
+            .on_conflict(
+                OnConflict::column(MilkLimits::Id)
+                    .update_columns([
+                        MilkLimits::Capacity,
+                        MilkLimits::Refill,
+                        MilkLimits::IntervalSeconds,
+                    ])
+                    .value(MilkLimits::UpdatedAt, Expr::current_timestamp())
+                    .to_owned(),
+            )
+            .build_sqlx(PostgresQueryBuilder);
+        sqlx::query_with(&sql, values).execute(&mut *tx).await?;
+
+        let (sql, values) = Query::update()
+            .table(MilkBuckets::Table)
+            .value(
+                MilkBuckets::Tokens,
+                Expr::cust_with_values("LEAST(tokens, $1)", [limits.capacity as i64]),
+            )
+            .build_sqlx(PostgresQueryBuilder);
+        sqlx::query_with(&sql, values).execute(&mut *tx).await?;
+
+        tx.commit().await
+    }
 
-    Ok((StatusCode::OK, "Milk withdrawn\n".to_string()))
+    /// Deletes buckets unused for `idle` that have refilled to capacity, as
+    /// recreating them later is indistinguishable from keeping them.
+    pub async fn sweep(pool: &PgPool, limits: &Limits, idle: Duration) -> Result<(), sqlx::Error> {
+        let (sql, values) = Query::delete()
+            .from_table(MilkBuckets::Table)
+            .and_where(Expr::col(MilkBuckets::UsedAt).lt(Utc::now() - idle))
+            .and_where(Expr::cust_with_values(
+                "tokens + FLOOR(EXTRACT(EPOCH FROM now() - refilled_at) / $1) * $2 >= $3",
+                [
+                    Value::from(limits.interval.as_secs_f64()),
+                    Value::from(limits.refill as i64),
+                    Value::from(limits.capacity as i64),
+                ],
+            ))
+            .build_sqlx(PostgresQueryBuilder);
+        sqlx::query_with(&sql, values).execute(pool).await?;
+
+        Ok(())
+    }
 }
 
-pub async fn refill(State(state): State<Milk>) -> impl IntoResponse {
-    let mut limiter = state.limiter.lock().unwrap();
-    *limiter = new_limiter();
+mod ledger {
+    use std::collections::BTreeMap;
+    use std::time::Duration;
+
+    use axum::http::StatusCode;
+    use sea_query::{Alias, Expr, Iden, Order, PostgresQueryBuilder, Query};
+    use sea_query_binder::SqlxBinder;
+    use serde::Serialize;
+    use sqlx::prelude::FromRow;
+    use sqlx::types::chrono::Utc;
+    use sqlx::PgPool;
+
+    use super::units::Conversion;
+
+    #[derive(Iden)]
+    enum MilkWithdrawals {
+        Table,
+        CreatedAt,
+        Client,
+        Tokens,
+        Amount,
+        FromUnit,
+        ToUnit,
+        RateLimited,
+        Status,
+    }
+
+    /// One call to `/9/milk`.
+    #[derive(Debug)]
+    pub struct Entry {
+        pub client: String,
+        pub tokens: usize,
+        pub conversion: Option<Conversion>,
+        /// What `/9/milk` answered.
+        pub status: StatusCode,
+    }
 
-    StatusCode::OK
+    /// Appends `entry` to the ledger. The ledger is for reporting only, so a
+    /// failed write never fails the withdrawal itself.
+    pub async fn record(pool: &PgPool, entry: &Entry) {
+        let conversion = entry.conversion.as_ref();
+        let (sql, values) = Query::insert()
+            .into_table(MilkWithdrawals::Table)
+            .columns([
+                MilkWithdrawals::Client,
+                MilkWithdrawals::Tokens,
+                MilkWithdrawals::Amount,
+                MilkWithdrawals::FromUnit,
+                MilkWithdrawals::ToUnit,
+                MilkWithdrawals::RateLimited,
+                MilkWithdrawals::Status,
+            ])
+            .values_panic([
+                entry.client.clone().into(),
+                i64::try_from(entry.tokens).unwrap_or(i64::MAX).into(),
+                conversion.map(|conversion| conversion.amount).into(),
+                conversion.map(|conversion| conversion.from).into(),
+                conversion.map(|conversion| conversion.to).into(),
+                (entry.status == StatusCode::TOO_MANY_REQUESTS).into(),
+                (entry.status.as_u16() as i16).into(),
+            ])
+            .build_sqlx(PostgresQueryBuilder);
+
+        let _ = sqlx::query_with(&sql, values).execute(pool).await;
+    }
+
+    #[derive(Debug, Serialize)]
+    pub struct Stats {
+        window: String,
+        requests: i64,
+        /// Turned away with a 429, until the bucket refills.
+        rate_limited: i64,
+        rate_limited_ratio: f64,
+        /// Turned away with a 413, as bigger than the bucket could ever hold.
+        too_large: i64,
+        tokens: i64,
+        /// Keyed by `from->to` unit names.
+        conversions: BTreeMap<String, Conversions>,
+    }
+
+    #[derive(Debug, Serialize)]
+    struct Conversions {
+        count: i64,
+        amount: f64,
+    }
+
+    #[derive(FromRow)]
+    struct Totals {
+        requests: i64,
+        rate_limited: i64,
+        too_large: i64,
+        tokens: i64,
+    }
+
+    #[derive(FromRow)]
+    struct UnitTotals {
+        from_unit: String,
+        to_unit: String,
+        count: i64,
+        amount: f64,
+    }
+
+    pub async fn stats(
+        pool: &PgPool,
+        window: &str,
+        duration: Duration,
+    ) -> Result<Stats, sqlx::Error> {
+        let since = Utc::now() - duration;
+
+        let (sql, values) = Query::select()
+            .expr_as(Expr::cust("COUNT(*)"), Alias::new("requests"))
+            .expr_as(
+                Expr::cust("COUNT(*) FILTER (WHERE rate_limited)"),
+                Alias::new("rate_limited"),
+            )
+            .expr_as(
+                Expr::cust("COUNT(*) FILTER (WHERE status = 413)"),
+                Alias::new("too_large"),
+            )
+            .expr_as(
+                Expr::cust(
+                    "COALESCE(SUM(tokens) FILTER (WHERE NOT rate_limited \
+                     AND status IS DISTINCT FROM 413), 0)::BIGINT",
+                ),
+                Alias::new("tokens"),
+            )
+            .from(MilkWithdrawals::Table)
+            .and_where(Expr::col(MilkWithdrawals::CreatedAt).gte(since))
+            .build_sqlx(PostgresQueryBuilder);
+        let totals = sqlx::query_as_with::<_, Totals, _>(&sql, values)
+            .fetch_one(pool)
+            .await?;
+
+        let (sql, values) = Query::select()
+            .columns([MilkWithdrawals::FromUnit, MilkWithdrawals::ToUnit])
+            .expr_as(Expr::cust("COUNT(*)"), Alias::new("count"))
+            .expr_as(
+                Expr::col(MilkWithdrawals::Amount).sum(),
+                Alias::new("amount"),
+            )
+            .from(MilkWithdrawals::Table)
+            .and_where(Expr::col(MilkWithdrawals::CreatedAt).gte(since))
+            .and_where(Expr::col(MilkWithdrawals::FromUnit).is_not_null())
+            .group_by_columns([MilkWithdrawals::FromUnit, MilkWithdrawals::ToUnit])
+            .order_by(MilkWithdrawals::FromUnit, Order::Asc)
+            .build_sqlx(PostgresQueryBuilder);
+        let units = sqlx::query_as_with::<_, UnitTotals, _>(&sql, values)
+            .fetch_all(pool)
+            .await?;
+
+        Ok(Stats {
+            window: window.to_string(),
+            requests: totals.requests,
+            rate_limited: totals.rate_limited,
+            rate_limited_ratio: match totals.requests {
+                0 => 0.0,
+                requests => totals.rate_limited as f64 / requests as f64,
+            },
+            too_large: totals.too_large,
+            tokens: totals.tokens,
+            conversions: units
+                .into_iter()
+                .map(|units| {
+                    let key = format!("{}->{}", units.from_unit, units.to_unit);
+                    let conversions = Conversions {
+                        count: units.count,
+                        amount: units.amount,
+                    };
+                    (key, conversions)
+                })
+                .collect(),
+        })
+    }
 }
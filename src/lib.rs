@@ -1 +1,3 @@
+mod accept;
 pub mod day;
+pub mod ratelimit;
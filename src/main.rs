@@ -1,24 +1,64 @@
-use axum::Router;
-use shuttle_runtime::CustomError;
+use std::env;
+use std::net::SocketAddr;
+
+use axum::{Extension, Router};
+use shuttle_runtime::{CustomError, SecretStore};
 use shuttlings_cch24::day;
+use shuttlings_cch24::ratelimit::{ClientConfig, RateLimitLayer};
 use sqlx::PgPool;
+use tokio::net::TcpListener;
 
 #[shuttle_runtime::main]
-async fn main(#[shuttle_shared_db::Postgres] pool: PgPool) -> shuttle_axum::ShuttleAxum {
+async fn main(
+    #[shuttle_shared_db::Postgres] pool: PgPool,
+    #[shuttle_runtime::Secrets] secrets: SecretStore,
+) -> Result<Service, shuttle_runtime::Error> {
     sqlx::migrate!()
         .run(&pool)
         .await
         .map_err(CustomError::new)?;
 
+    let policy = day::d5::Policy::load("manifest_policy.toml").map_err(CustomError::msg)?;
+
+    let var = |key: &str| secrets.get(key).or_else(|| env::var(key).ok());
+    let milk = day::d9::Config::from_vars(var).map_err(CustomError::msg)?;
+    let limits = RateLimitLayer::load("rate_limits.toml").map_err(CustomError::msg)?;
+    let clients = ClientConfig::parse(
+        &var("RATE_LIMIT_API_KEYS").unwrap_or_default(),
+        &var("RATE_LIMIT_TRUSTED_PROXIES").unwrap_or_default(),
+    )
+    .map_err(CustomError::msg)?;
+
     let router = Router::new()
         .merge(day::d_1::get_routes())
         .merge(day::d2::get_routes())
-        .merge(day::d5::get_routes())
-        .merge(day::d9::get_routes())
+        .merge(day::d5::get_routes(policy))
+        .merge(day::d9::get_routes(milk, pool.clone()))
         .merge(day::d12::get_routes())
         .merge(day::d16::get_routes())
         .merge(day::d19::get_routes(pool.clone()))
-        .merge(day::d23::get_routes());
+        .merge(day::d23::get_routes())
+        .route_layer(limits)
+        .layer(Extension(clients));
+
+    Ok(Service(router))
+}
+
+/// Serves the app like `shuttle_axum` does, but with the peer address
+/// available to handlers, which rate limiting falls back on.
+struct Service(Router);
+
+#[shuttle_runtime::async_trait]
+impl shuttle_runtime::Service for Service {
+    async fn bind(self, addr: SocketAddr) -> Result<(), shuttle_runtime::Error> {
+        let listener = TcpListener::bind(addr).await.map_err(CustomError::new)?;
+        axum::serve(
+            listener,
+            self.0.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .await
+        .map_err(CustomError::new)?;
 
-    Ok(router.into())
+        Ok(())
+    }
 }
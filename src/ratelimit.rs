@@ -0,0 +1,833 @@
+//! Per-client token buckets, usable directly from a handler or as a tower
+//! [`Layer`] with limits per route.
+
+use std::collections::{HashMap, HashSet};
+use std::convert::Infallible;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use axum::extract::{ConnectInfo, FromRequestParts, MatchedPath, Request};
+use axum::http::header::RETRY_AFTER;
+use axum::http::request::Parts;
+use axum::http::{Extensions, HeaderMap, Method, StatusCode};
+use axum::response::{IntoResponse, IntoResponseParts, Response, ResponseParts};
+use futures_util::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DurationSecondsWithFrac};
+use sqlx::types::chrono::{DateTime, Utc};
+use tower::{Layer, Service};
+
+/// Buckets untouched for this long are dropped once they have refilled, so
+/// a returning client sees no difference.
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The shape of a bucket: it holds at most `capacity` tokens and gains
+/// `refill` of them every `interval`.
+#[serde_as]
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Limits {
+    pub capacity: usize,
+    pub refill: usize,
+    #[serde_as(as = "DurationSecondsWithFrac<f64>")]
+    pub interval: Duration,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            capacity: 5,
+            refill: 1,
+            interval: Duration::from_secs(1),
+        }
+    }
+}
+
+impl Limits {
+    /// Keeps token counts well inside what Postgres and header values hold.
+    pub const MAX_TOKENS: usize = 1_000_000;
+    /// Keeps refill times well inside what `Instant` and `DateTime` hold.
+    pub const MAX_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+    pub fn validate(&self) -> Result<(), String> {
+        if !(1..=Self::MAX_TOKENS).contains(&self.capacity) {
+            return Err(format!(
+                "capacity must be between 1 and {}",
+                Self::MAX_TOKENS
+            ));
+        }
+        if !(1..=Self::MAX_TOKENS).contains(&self.refill) {
+            return Err(format!("refill must be between 1 and {}", Self::MAX_TOKENS));
+        }
+        if self.interval.is_zero() || self.interval > Self::MAX_INTERVAL {
+            return Err(format!(
+                "interval must be positive and at most {} seconds",
+                Self::MAX_INTERVAL.as_secs()
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// A bucket kept outside the process, so its refill time is wall-clock.
+#[derive(Debug, Clone, Copy)]
+pub struct Snapshot {
+    pub tokens: usize,
+    pub refilled: DateTime<Utc>,
+}
+
+impl Snapshot {
+    pub fn full(limits: &Limits) -> Self {
+        Self {
+            tokens: limits.capacity,
+            refilled: Utc::now(),
+        }
+    }
+
+    /// Takes `tokens` from the stored bucket, returning the outcome as
+    /// [`RateLimiter::acquire`] does and the bucket to store back.
+    pub fn acquire(self, limits: &Limits, tokens: usize) -> (Result<Quota, Quota>, Snapshot) {
+        let (now, wall) = (Instant::now(), Utc::now());
+        let elapsed = (wall - self.refilled).to_std().unwrap_or_default();
+        // Long enough ago not to fit in an `Instant`, so certainly refilled.
+        let mut bucket = match now.checked_sub(elapsed) {
+            Some(refilled) => Bucket {
+                tokens: self.tokens,
+                refilled,
+                used: now,
+            },
+            None => Bucket::full(limits, now),
+        };
+
+        let acquired = bucket.try_acquire(limits, now, tokens);
+        let quota = bucket.quota(limits, now, tokens);
+        // The bucket never refilled before the stored time, so this can't
+        // go out of range.
+        let snapshot = Snapshot {
+            tokens: bucket.tokens,
+            refilled: wall - now.duration_since(bucket.refilled),
+        };
+
+        (if acquired { Ok(quota) } else { Err(quota) }, snapshot)
+    }
+}
+
+/// What [`Client`] may believe about a request: the `X-Api-Key` values we
+/// bill separately, and the proxies whose `X-Forwarded-For` we trust. Anyone
+/// can make up a key or a forwarded address, so those from elsewhere are
+/// ignored rather than given a bucket of their own.
+///
+/// Put it in the request extensions, e.g. with an [`axum::Extension`] layer
+/// around the whole app, for [`Client`] to see it.
+#[derive(Debug, Clone, Default)]
+pub struct ClientConfig {
+    api_keys: Arc<HashSet<String>>,
+    trusted_proxies: Arc<HashSet<IpAddr>>,
+}
+
+impl ClientConfig {
+    /// Reads comma-separated lists of API keys and trusted proxy addresses.
+    pub fn parse(api_keys: &str, trusted_proxies: &str) -> Result<Self, String> {
+        let list = |list: &str| {
+            list.split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(str::to_string)
+                .collect::<Vec<_>>()
+        };
+
+        let trusted_proxies = list(trusted_proxies)
+            .into_iter()
+            .map(|proxy| {
+                proxy
+                    .parse()
+                    .map_err(|_| format!("Invalid trusted proxy address: {proxy}"))
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            api_keys: Arc::new(list(api_keys).into_iter().collect()),
+            trusted_proxies: Arc::new(trusted_proxies),
+        })
+    }
+
+    fn trusts(&self, ip: IpAddr) -> bool {
+        self.trusted_proxies.contains(&ip)
+    }
+}
+
+/// Who a request is billed to: the `X-Api-Key` header if it is one of the
+/// configured keys, otherwise the peer address. When the peer is a trusted
+/// proxy, the address it forwarded for is used instead: the rightmost
+/// `X-Forwarded-For` hop, skipping any other trusted proxies before it.
+///
+/// The peer address needs the app served with
+/// `into_make_service_with_connect_info::<SocketAddr>()`. Without it, requests
+/// share a single bucket. See [`ClientConfig`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Client {
+    Key(String),
+    Ip(IpAddr),
+    Unknown,
+}
+
+impl Client {
+    fn identify(headers: &HeaderMap, extensions: &Extensions) -> Self {
+        let default = ClientConfig::default();
+        let config = extensions.get::<ClientConfig>().unwrap_or(&default);
+
+        let key = headers
+            .get("X-Api-Key")
+            .and_then(|value| value.to_str().ok())
+            .map(str::trim)
+            .filter(|key| config.api_keys.contains(*key));
+        if let Some(key) = key {
+            return Client::Key(key.to_string());
+        }
+
+        let Some(ConnectInfo(peer)) = extensions.get::<ConnectInfo<SocketAddr>>() else {
+            return Client::Unknown;
+        };
+
+        // Each trusted proxy appends the address it got the request from, so
+        // read from the right until an address we don't trust to forward.
+        let mut ip = peer.ip();
+        let hops = headers
+            .get_all("X-Forwarded-For")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .collect::<Vec<_>>();
+        for hop in hops.into_iter().rev() {
+            if !config.trusts(ip) {
+                break;
+            }
+            match hop.trim().parse() {
+                Ok(hop) => ip = hop,
+                Err(_) => break,
+            }
+        }
+
+        Client::Ip(ip)
+    }
+}
+
+impl fmt::Display for Client {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Client::Key(key) => write!(f, "key:{key}"),
+            Client::Ip(ip) => write!(f, "ip:{ip}"),
+            Client::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+impl<S> FromRequestParts<S> for Client
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(Self::identify(&parts.headers, &parts.extensions))
+    }
+}
+
+/// A token bucket that gains `refill` tokens every whole `interval` since
+/// `refilled`, up to `capacity`.
+#[derive(Debug)]
+struct Bucket {
+    tokens: usize,
+    refilled: Instant,
+    used: Instant,
+}
+
+impl Bucket {
+    fn full(limits: &Limits, now: Instant) -> Self {
+        Self {
+            tokens: limits.capacity,
+            refilled: now,
+            used: now,
+        }
+    }
+
+    fn update(&mut self, limits: &Limits, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled);
+        let ticks = elapsed.as_nanos() / limits.interval.as_nanos().max(1);
+        let missing = limits.capacity.saturating_sub(self.tokens) as u128;
+
+        // Fewer ticks than tokens missing, so these fit a `u32` and `usize`.
+        let refilled = u32::try_from(ticks)
+            .ok()
+            .and_then(|ticks| limits.interval.checked_mul(ticks))
+            .and_then(|since| self.refilled.checked_add(since));
+        match refilled {
+            Some(refilled) if ticks.saturating_mul(limits.refill as u128) < missing => {
+                if ticks > 0 {
+                    self.tokens += ticks as usize * limits.refill;
+                    self.refilled = refilled;
+                }
+            }
+            // A full bucket doesn't accumulate ticks.
+            _ => {
+                self.tokens = limits.capacity;
+                self.refilled = now;
+            }
+        }
+    }
+
+    fn try_acquire(&mut self, limits: &Limits, now: Instant, tokens: usize) -> bool {
+        self.update(limits, now);
+        if self.tokens < tokens {
+            return false;
+        }
+        self.tokens -= tokens;
+        true
+    }
+
+    /// Time until the bucket holds at least `tokens`, or [`Duration::MAX`] if
+    /// that is too far off to represent.
+    fn wait(&self, limits: &Limits, now: Instant, tokens: usize) -> Duration {
+        let missing = tokens.saturating_sub(self.tokens);
+        if missing == 0 {
+            return Duration::ZERO;
+        }
+        u32::try_from(missing.div_ceil(limits.refill.max(1)))
+            .ok()
+            .and_then(|ticks| limits.interval.checked_mul(ticks))
+            .and_then(|until| self.refilled.checked_add(until))
+            .map_or(Duration::MAX, |ready| ready.saturating_duration_since(now))
+    }
+
+    /// The bucket's state, for a client that wants `tokens` next.
+    fn quota(&self, limits: &Limits, now: Instant, tokens: usize) -> Quota {
+        Quota {
+            limit: limits.capacity,
+            remaining: self.tokens,
+            reset: self.wait(limits, now, limits.capacity),
+            retry_after: self.wait(limits, now, tokens),
+        }
+    }
+}
+
+/// The `RateLimit-*` and `Retry-After` headers describing a bucket.
+#[derive(Debug)]
+pub struct Quota {
+    pub limit: usize,
+    pub remaining: usize,
+    pub reset: Duration,
+    pub retry_after: Duration,
+}
+
+impl IntoResponseParts for Quota {
+    type Error = Infallible;
+
+    fn into_response_parts(self, mut res: ResponseParts) -> Result<ResponseParts, Self::Error> {
+        // Header values are whole seconds, rounded up so clients never retry early.
+        let seconds = |duration: Duration| {
+            (duration
+                .as_secs()
+                .saturating_add(u64::from(duration.subsec_nanos() > 0)))
+            .into()
+        };
+
+        let headers = res.headers_mut();
+        headers.insert("ratelimit-limit", self.limit.into());
+        headers.insert("ratelimit-remaining", self.remaining.into());
+        headers.insert("ratelimit-reset", seconds(self.reset));
+        headers.insert(RETRY_AFTER, seconds(self.retry_after));
+        Ok(res)
+    }
+}
+
+#[derive(Debug)]
+struct Buckets {
+    clients: HashMap<Client, Bucket>,
+    swept: Instant,
+}
+
+impl Buckets {
+    /// Returns the bucket for `client`, creating a full one on first use.
+    fn get(&mut self, limits: &Limits, client: &Client, now: Instant) -> &mut Bucket {
+        if now.duration_since(self.swept) >= IDLE_TIMEOUT {
+            self.clients.retain(|_, bucket| {
+                now.duration_since(bucket.used) < IDLE_TIMEOUT
+                    || !bucket.wait(limits, now, limits.capacity).is_zero()
+            });
+            self.swept = now;
+        }
+
+        let bucket = self
+            .clients
+            .entry(client.clone())
+            .or_insert_with(|| Bucket::full(limits, now));
+        bucket.used = now;
+        bucket
+    }
+}
+
+/// One bucket per [`Client`], all shaped by the same [`Limits`].
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    inner: Arc<Mutex<(Limits, Buckets)>>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: Limits) -> Self {
+        let buckets = Buckets {
+            clients: HashMap::new(),
+            swept: Instant::now(),
+        };
+        Self {
+            inner: Arc::new(Mutex::new((limits, buckets))),
+        }
+    }
+
+    /// Takes `tokens` from the client's bucket. Either way the bucket's state
+    /// afterwards is returned, ready to be sent as headers.
+    pub fn acquire(&self, client: &Client, tokens: usize) -> Result<Quota, Quota> {
+        let now = Instant::now();
+        let mut inner = self.inner.lock().unwrap();
+        let (limits, buckets) = &mut *inner;
+        let bucket = buckets.get(limits, client, now);
+
+        if bucket.try_acquire(limits, now, tokens) {
+            Ok(bucket.quota(limits, now, tokens))
+        } else {
+            Err(bucket.quota(limits, now, tokens))
+        }
+    }
+}
+
+/// The requests one [`RateLimiter`] applies to.
+#[derive(Debug, Clone)]
+struct Rule {
+    /// The route as registered with the router, e.g. `/19/undo/{id}`.
+    path: String,
+    /// Any method if empty.
+    methods: Vec<Method>,
+    limiter: RateLimiter,
+}
+
+impl Rule {
+    fn matches(&self, path: Option<&str>, method: &Method) -> bool {
+        path == Some(self.path.as_str())
+            && (self.methods.is_empty() || self.methods.contains(method))
+    }
+}
+
+/// Rate limits requests to the wrapped service by the first rule they match.
+/// Requests matching no rule go through untouched.
+///
+/// Rules match on the route, so add the layer with [`Router::route_layer`]
+/// for the matched route to be known.
+#[derive(Debug, Clone)]
+pub struct RateLimitLayer {
+    rules: Arc<[Rule]>,
+}
+
+impl RateLimitLayer {
+    /// Reads per-route limits from a TOML file, see `rate_limits.toml`. No
+    /// file means no limits.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, String> {
+        let path = path.as_ref();
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Ok(Self {
+                    rules: Arc::new([]),
+                })
+            }
+            Err(e) => return Err(format!("Failed to read {}: {e}", path.display())),
+        };
+
+        let config: RateLimitsConfig = toml::from_str(&content)
+            .map_err(|e| format!("Invalid rate limits {}: {e}", path.display()))?;
+
+        let rules = config
+            .route
+            .into_iter()
+            .map(|route| {
+                let name = &route.path;
+                if !name.starts_with('/') {
+                    return Err(format!("Invalid rate limit path {name}: must start with /"));
+                }
+                route
+                    .limits
+                    .validate()
+                    .map_err(|e| format!("Invalid rate limit {name}: {e}"))?;
+                let methods = route
+                    .methods
+                    .iter()
+                    .map(|method| {
+                        Method::from_bytes(method.to_uppercase().as_bytes())
+                            .map_err(|_| format!("Invalid method in rate limit {name}: {method}"))
+                    })
+                    .collect::<Result<_, _>>()?;
+
+                Ok(Rule {
+                    limiter: RateLimiter::new(route.limits),
+                    path: route.path,
+                    methods,
+                })
+            })
+            .collect::<Result<_, String>>()?;
+
+        Ok(Self { rules })
+    }
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimit<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimit {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RateLimit<S> {
+    inner: S,
+    layer: RateLimitLayer,
+}
+
+impl<S> Service<Request> for RateLimit<S>
+where
+    S: Service<Request, Response = Response, Error = Infallible>,
+    S::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = Infallible;
+    type Future = BoxFuture<'static, Result<Response, Infallible>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        let path = req
+            .extensions()
+            .get::<MatchedPath>()
+            .map(MatchedPath::as_str);
+        let rule = self
+            .layer
+            .rules
+            .iter()
+            .find(|rule| rule.matches(path, req.method()));
+        let Some(rule) = rule else {
+            return Box::pin(self.inner.call(req));
+        };
+
+        let client = Client::identify(req.headers(), req.extensions());
+        match rule.limiter.acquire(&client, 1) {
+            Ok(quota) => {
+                let response = self.inner.call(req);
+                Box::pin(async move { Ok((quota, response.await?).into_response()) })
+            }
+            Err(quota) => {
+                let response = (StatusCode::TOO_MANY_REQUESTS, quota, "Too many requests\n");
+                Box::pin(async move { Ok(response.into_response()) })
+            }
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RateLimitsConfig {
+    #[serde(default)]
+    route: Vec<RouteConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RouteConfig {
+    path: String,
+    #[serde(default)]
+    methods: Vec<String>,
+    #[serde(flatten)]
+    limits: Limits,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    fn limits(capacity: usize, refill: usize, interval: Duration) -> Limits {
+        Limits {
+            capacity,
+            refill,
+            interval,
+        }
+    }
+
+    fn empty(now: Instant) -> Bucket {
+        Bucket {
+            tokens: 0,
+            refilled: now,
+            used: now,
+        }
+    }
+
+    #[test]
+    fn refills_whole_intervals_only() {
+        let limits = limits(5, 2, SECOND);
+        let start = Instant::now();
+        let mut bucket = empty(start);
+
+        bucket.update(&limits, start + SECOND / 2);
+        assert_eq!(bucket.tokens, 0);
+        assert_eq!(bucket.refilled, start);
+
+        bucket.update(&limits, start + SECOND * 2 + SECOND / 2);
+        assert_eq!(bucket.tokens, 4);
+        // The half interval left over still counts towards the next refill.
+        assert_eq!(bucket.refilled, start + SECOND * 2);
+
+        bucket.update(&limits, start + SECOND * 3);
+        assert_eq!(bucket.tokens, 5);
+    }
+
+    #[test]
+    fn caps_at_capacity() {
+        let limits = limits(5, 1, SECOND);
+        let start = Instant::now();
+        let mut bucket = empty(start);
+
+        let later = start + SECOND * 100;
+        bucket.update(&limits, later);
+        assert_eq!(bucket.tokens, 5);
+        // A full bucket starts counting afresh.
+        assert_eq!(bucket.refilled, later);
+
+        assert!(bucket.try_acquire(&limits, later, 1));
+        bucket.update(&limits, later + SECOND / 2);
+        assert_eq!(bucket.tokens, 4);
+    }
+
+    #[test]
+    fn acquires_several_tokens_or_none() {
+        let limits = limits(5, 1, SECOND);
+        let start = Instant::now();
+        let mut bucket = Bucket::full(&limits, start);
+
+        assert!(bucket.try_acquire(&limits, start, 3));
+        assert_eq!(bucket.tokens, 2);
+        assert!(!bucket.try_acquire(&limits, start, 3));
+        assert_eq!(bucket.tokens, 2);
+    }
+
+    #[test]
+    fn waits_for_enough_ticks() {
+        let limits = limits(10, 2, SECOND);
+        let start = Instant::now();
+        let mut bucket = empty(start);
+        bucket.tokens = 1;
+
+        assert_eq!(bucket.wait(&limits, start, 1), Duration::ZERO);
+        // 3 more tokens take 2 refills of 2.
+        assert_eq!(bucket.wait(&limits, start, 4), SECOND * 2);
+        assert_eq!(
+            bucket.wait(&limits, start + SECOND / 4, 4),
+            SECOND * 2 - SECOND / 4
+        );
+        // 9 more tokens take 5 refills.
+        assert_eq!(bucket.wait(&limits, start, limits.capacity), SECOND * 5);
+    }
+
+    #[test]
+    fn quota_reports_reset_and_retry() {
+        let limits = limits(5, 1, SECOND);
+        let start = Instant::now();
+        let mut bucket = empty(start);
+        bucket.tokens = 3;
+
+        let quota = bucket.quota(&limits, start + SECOND / 2, 4);
+        assert_eq!(quota.limit, 5);
+        assert_eq!(quota.remaining, 3);
+        assert_eq!(quota.reset, SECOND * 2 - SECOND / 2);
+        assert_eq!(quota.retry_after, SECOND / 2);
+    }
+
+    #[test]
+    fn headers_round_up_to_whole_seconds() {
+        let header = |duration: Duration| {
+            let quota = Quota {
+                limit: 5,
+                remaining: 2,
+                reset: duration,
+                retry_after: Duration::ZERO,
+            };
+            let response = (quota, ()).into_response();
+            let headers = response.headers().clone();
+            assert_eq!(headers["ratelimit-limit"], "5");
+            assert_eq!(headers["ratelimit-remaining"], "2");
+            assert_eq!(headers[RETRY_AFTER], "0");
+            headers["ratelimit-reset"].to_str().unwrap().to_string()
+        };
+
+        assert_eq!(header(Duration::ZERO), "0");
+        assert_eq!(header(Duration::from_nanos(1)), "1");
+        assert_eq!(header(SECOND), "1");
+        assert_eq!(header(SECOND + Duration::from_millis(1)), "2");
+        assert_eq!(header(Duration::MAX), u64::MAX.to_string());
+    }
+
+    #[test]
+    fn survives_the_largest_limits() {
+        let limits = limits(Limits::MAX_TOKENS, 1, Limits::MAX_INTERVAL);
+        assert_eq!(limits.validate(), Ok(()));
+
+        let start = Instant::now();
+        let mut bucket = empty(start);
+        let quota = bucket.quota(&limits, start, Limits::MAX_TOKENS);
+        assert!(quota.reset > Limits::MAX_INTERVAL);
+        bucket.update(&limits, start + Limits::MAX_INTERVAL * 3);
+        assert_eq!(bucket.tokens, 3);
+    }
+
+    #[test]
+    fn rejects_unbounded_limits() {
+        assert!(limits(0, 1, SECOND).validate().is_err());
+        assert!(limits(Limits::MAX_TOKENS + 1, 1, SECOND)
+            .validate()
+            .is_err());
+        assert!(limits(5, 0, SECOND).validate().is_err());
+        assert!(limits(5, 1, Duration::ZERO).validate().is_err());
+        assert!(limits(5, 1, Limits::MAX_INTERVAL + SECOND)
+            .validate()
+            .is_err());
+    }
+
+    #[test]
+    fn snapshot_refills_from_wall_clock() {
+        let limits = limits(5, 1, SECOND);
+        let stored = Snapshot {
+            tokens: 0,
+            refilled: Utc::now() - SECOND * 3 - SECOND / 2,
+        };
+
+        let (outcome, snapshot) = stored.acquire(&limits, 1);
+        let quota = outcome.unwrap();
+        assert_eq!(quota.remaining, 2);
+        assert_eq!(snapshot.tokens, 2);
+
+        // The unused half interval carries over, give or take the clock
+        // ticking during the test.
+        let drift = (snapshot.refilled - (stored.refilled + SECOND * 3))
+            .num_milliseconds()
+            .abs();
+        assert!(drift < 100, "refilled {drift}ms off");
+    }
+
+    #[test]
+    fn snapshot_denies_without_taking() {
+        let limits = limits(5, 1, SECOND);
+        let stored = Snapshot {
+            tokens: 1,
+            refilled: Utc::now(),
+        };
+
+        let (outcome, snapshot) = stored.acquire(&limits, 2);
+        let quota = outcome.unwrap_err();
+        assert_eq!(quota.remaining, 1);
+        assert!(quota.retry_after <= SECOND);
+        assert_eq!(snapshot.tokens, 1);
+    }
+
+    #[test]
+    fn snapshot_from_long_ago_is_full() {
+        let limits = limits(5, 1, SECOND);
+        let stored = Snapshot {
+            tokens: 0,
+            refilled: DateTime::<Utc>::MIN_UTC,
+        };
+
+        let (outcome, snapshot) = stored.acquire(&limits, 1);
+        assert!(outcome.is_ok());
+        assert_eq!(snapshot.tokens, 4);
+    }
+
+    fn identify(peer: Option<&str>, headers: &[(&'static str, &str)]) -> Client {
+        let mut extensions = Extensions::new();
+        extensions.insert(ClientConfig::parse("known", "10.0.0.1, 10.0.0.2").unwrap());
+        if let Some(peer) = peer {
+            let peer: IpAddr = peer.parse().unwrap();
+            extensions.insert(ConnectInfo(SocketAddr::new(peer, 4321)));
+        }
+
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            map.append(*name, value.parse().unwrap());
+        }
+        Client::identify(&map, &extensions)
+    }
+
+    fn ip(ip: &str) -> Client {
+        Client::Ip(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn identifies_configured_keys_only() {
+        let known = [("X-Api-Key", "known")];
+        assert_eq!(identify(None, &known), Client::Key("known".to_string()));
+
+        let made_up = [("X-Api-Key", "made-up")];
+        assert_eq!(identify(Some("192.0.2.7"), &made_up), ip("192.0.2.7"));
+        assert_eq!(identify(None, &made_up), Client::Unknown);
+    }
+
+    #[test]
+    fn ignores_forwarded_for_from_untrusted_peers() {
+        let spoofed = [("X-Forwarded-For", "198.51.100.1")];
+        assert_eq!(identify(Some("192.0.2.7"), &spoofed), ip("192.0.2.7"));
+        assert_eq!(identify(None, &spoofed), Client::Unknown);
+    }
+
+    #[test]
+    fn takes_the_hop_a_trusted_proxy_added() {
+        // The client made up the first hop, the proxy appended the second.
+        let forwarded = [("X-Forwarded-For", "198.51.100.1, 192.0.2.7")];
+        assert_eq!(identify(Some("10.0.0.1"), &forwarded), ip("192.0.2.7"));
+
+        // Through two trusted proxies, one header each.
+        let chained = [
+            ("X-Forwarded-For", "198.51.100.1, 192.0.2.7"),
+            ("X-Forwarded-For", "10.0.0.2"),
+        ];
+        assert_eq!(identify(Some("10.0.0.1"), &chained), ip("192.0.2.7"));
+
+        let garbled = [("X-Forwarded-For", "192.0.2.7, nonsense")];
+        assert_eq!(identify(Some("10.0.0.1"), &garbled), ip("10.0.0.1"));
+        assert_eq!(identify(Some("10.0.0.1"), &[]), ip("10.0.0.1"));
+    }
+
+    #[test]
+    fn rejects_invalid_proxy_addresses() {
+        assert!(ClientConfig::parse("", "10.0.0.1, proxy.local").is_err());
+        assert!(ClientConfig::parse("", " , ").is_ok());
+    }
+
+    #[test]
+    fn snapshot_from_the_future_does_not_refill() {
+        let limits = limits(5, 1, SECOND);
+        let stored = Snapshot {
+            tokens: 0,
+            refilled: Utc::now() + SECOND * 60,
+        };
+
+        let (outcome, snapshot) = stored.acquire(&limits, 1);
+        assert!(outcome.is_err());
+        assert_eq!(snapshot.tokens, 0);
+    }
+}